@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+use ansi_term::Color::{Green, Red, RGB};
+use ansi_term::Style;
+use clap::ArgMatches;
+
+use config::format::ConfigFormat;
+use config::layers;
+use config::ConfigSource;
+use config::validate;
+use error::KakapoError;
+use output;
+use output::{OutputFormat, REDACTED};
+use wizard::utils;
+use wizard::ConfigData;
+
+#[derive(Serialize, Clone, Debug)]
+struct ValueOrigin {
+    path: String,
+    value: serde_yaml::Value,
+    origin: PathBuf,
+}
+
+/// Every value of the merged config, with the file it came from
+fn show_origins(files: &[PathBuf], output: OutputFormat) -> Result<(), KakapoError> {
+    let layered = layers::load(files)?;
+    let mut leaves = BTreeMap::new();
+    layers::leaves(&layered.value, "", &mut leaves);
+
+    let origins: Vec<_> = layered.origins
+        .into_iter()
+        .map(|(path, origin)| {
+            let key = path.rsplit('.').next().unwrap_or_default();
+            let value = match leaves.remove(&path) {
+                Some(serde_yaml::Value::String(ref secret)) if output::is_secret(key) && !secret.is_empty() =>
+                    serde_yaml::Value::String(REDACTED.to_string()),
+                Some(value) => value,
+                None => serde_yaml::Value::Null,
+            };
+            ValueOrigin { path, value, origin }
+        })
+        .collect();
+
+    output::print(output, &origins, || {
+        for value_origin in &origins {
+            let value = serde_yaml::to_string(&value_origin.value)
+                .map(|value| value.trim_start_matches("---").trim().to_string())
+                .unwrap_or_default();
+            println!("{: <40} {: <32} {}",
+                     &value_origin.path, value, Style::new().dimmed().paint(value_origin.origin.to_string_lossy()));
+        }
+    })
+}
+
+fn show(source: &ConfigSource, output: OutputFormat) -> Result<(), KakapoError> {
+    let config = ConfigData::load(source)?;
+    let text = serde_yaml::to_string(&output::redacted(&config)?)
+        .map_err(|err| KakapoError::Other(format!("Could not serialize the configuration: {}", err)))?;
+
+    output::print(output, &config, || {
+        for file in &source.files {
+            println!("{}", Style::new().bold().paint(format!("# {}", file.display())));
+        }
+        if let Some(ref profile) = source.profile {
+            println!("{}", Style::new().bold().paint(format!("# profile {}", profile)));
+        }
+        println!("{}", text);
+    })
+}
+
+fn validate(source: &ConfigSource, output: OutputFormat) -> Result<(), KakapoError> {
+    let validation = validate::validate_layers(source)?;
+
+    output::print(output, &validation, || {
+        if validation.valid {
+            for file in &validation.files {
+                println!("{} is valid", Green.paint(file.to_string_lossy()));
+            }
+        }
+        for issue in &validation.issues {
+            let field = if issue.field.is_empty() { "(file)" } else { issue.field.as_str() };
+            println!("{}: {}", Red.paint(field), &issue.message);
+        }
+    })?;
+
+    if validation.valid {
+        Ok(())
+    } else {
+        Err(KakapoError::ConfigParse {
+            path: source.files.last().cloned().unwrap_or_default(),
+            message: format!("found {} issues", validation.issues.len()),
+        })
+    }
+}
+
+/// Always structured, yaml if asked for and json otherwise
+fn schema(output: OutputFormat) -> Result<(), KakapoError> {
+    let schema = validate::schema();
+    let result = match output {
+        OutputFormat::Yaml => serde_yaml::to_string(&schema).map_err(|err| err.to_string()),
+        _ => serde_json::to_string_pretty(&schema).map_err(|err| err.to_string()),
+    };
+
+    let schema = result
+        .map_err(|err| KakapoError::Other(format!("Could not serialize the schema: {}", err)))?;
+    println!("{}", schema);
+
+    Ok(())
+}
+
+/// Prints the config in another format, or with `--write` replaces the config file with one in the new format
+fn convert(matches: &ArgMatches, config_path: &Path) -> Result<(), KakapoError> {
+    let format: ConfigFormat = matches
+        .value_of("to")
+        .unwrap_or_default()
+        .parse()
+        .map_err(KakapoError::Usage)?;
+    let config = ConfigData::from_file(config_path.to_owned())?;
+
+    if !matches.is_present("write") {
+        let text = format.render(&config)
+            .map_err(|err| KakapoError::Other(format!("Could not serialize the configuration: {}", err)))?;
+        println!("{}", text);
+        return Ok(());
+    }
+
+    let new_path = config_path.with_extension(format.extension());
+    if new_path == config_path {
+        return Err(KakapoError::Usage(format!("{} is already {}", config_path.display(), format.extension())));
+    }
+
+    config.with_path(new_path.to_owned()).to_file()?;
+
+    // the old file would still be found before the new one
+    let backup_path = PathBuf::from(format!("{}.bak", config_path.display()));
+    if let Err(err) = utils::move_file(config_path, &backup_path) {
+        // leave only the old file behind rather than two configs in different formats
+        if let Err(remove_err) = utils::remove_file(&new_path) {
+            eprintln!("{}", Red.paint(format!("Could not remove {}: {}", new_path.display(), remove_err)));
+        }
+        return Err(err);
+    }
+
+    println!("Configuration converted to {}, the old one was moved to {}",
+             RGB(131, 221, 2).bold().paint(new_path.to_string_lossy()),
+             backup_path.display());
+
+    Ok(())
+}
+
+/// `kakapo config ...`
+pub fn run(matches: &ArgMatches, source: &ConfigSource, output: OutputFormat) -> Result<(), KakapoError> {
+    match matches.subcommand() {
+        ("show", Some(show_matches)) if show_matches.is_present("origin") => show_origins(&source.files, output),
+        ("show", _) => show(source, output),
+        ("validate", _) => validate(source, output),
+        ("schema", _) => schema(output),
+        ("convert", Some(convert_matches)) => convert(convert_matches, &source.path),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,491 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use ansi_term::Color::{Green, Red, Yellow, RGB};
+use clap::ArgMatches;
+use postgres::{Connection, TlsMode};
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use postgres::params::{ConnectParams, Host};
+use postgres::tls::openssl::OpenSsl;
+
+use config::ConfigSource;
+use error::KakapoError;
+use output;
+use output::OutputFormat;
+use tunnel;
+use wizard;
+use wizard::connection;
+use wizard::connection::{ConnectionUrl, PostgresConnection};
+use wizard::import;
+use wizard::import::Candidate;
+use wizard::prompt::{Confirmation, Input, PasswordInput};
+use wizard::utils;
+use wizard::ConfigData;
+use wizard::DomainInfo;
+use wizard::{PostgresOptions, SslMode};
+
+const CONNECT_TIMEOUT_SECS: u64 = 5;
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_millis() as u64
+}
+
+/// The ssh hop in front of a domain behind a bastion
+#[derive(Serialize, Clone, Debug)]
+pub struct TunnelHop {
+    pub via: String,
+    pub ok: bool,
+    pub latency_ms: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ConnectionTest {
+    pub name: String,
+    pub target: String,
+    pub ok: bool,
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tunnel: Option<TunnelHop>,
+}
+
+impl ConnectionTest {
+    pub fn new(name: &str, target: String, result: Result<Duration, String>) -> Self {
+        let (latency_ms, error) = match result {
+            Ok(latency) => (Some(millis(latency)), None),
+            Err(err) => (None, Some(err)),
+        };
+
+        ConnectionTest {
+            name: name.to_string(),
+            target,
+            ok: error.is_none(),
+            latency_ms,
+            error,
+            tunnel: None,
+        }
+    }
+
+    pub fn print_text(&self) {
+        let status = match (self.latency_ms, &self.error) {
+            (Some(latency_ms), _) => Green.paint(format!("ok ({} ms)", latency_ms)),
+            (_, Some(err)) => Red.paint(format!("failed: {}", err)),
+            _ => Red.paint("failed"),
+        };
+        println!("{}=> {} {}", RGB(131, 221, 2).bold().paint(format!("{: <16}", &self.name)), &self.target, status);
+
+        if let Some(ref tunnel) = self.tunnel {
+            let status = match tunnel.latency_ms {
+                Some(latency_ms) if tunnel.ok => Green.paint(format!("ok ({} ms)", latency_ms)),
+                _ => Red.paint("failed".to_string()),
+            };
+            println!("{: <16}   via {} {}", "", &tunnel.via, status);
+        }
+    }
+}
+
+/// The TLS setup for the sslmode, `require` only encrypts like it does in libpq
+fn postgres_tls(options: &PostgresOptions) -> Result<Option<OpenSsl>, String> {
+    let sslmode = options.sslmode();
+    if sslmode == SslMode::Disable {
+        return Ok(None);
+    }
+
+    let mut connector = SslConnector::builder(SslMethod::tls())
+        .map_err(|err| err.to_string())?;
+    if sslmode.verifies_certificate() {
+        if let Some(ref ssl_root_cert) = options.ssl_root_cert {
+            connector.set_ca_file(ssl_root_cert)
+                .map_err(|err| format!("Could not read {}: {}", ssl_root_cert.display(), err))?;
+        }
+    } else {
+        connector.set_verify(SslVerifyMode::NONE);
+    }
+
+    let mut tls = OpenSsl::from(connector.build());
+    tls.danger_disable_hostname_verification(sslmode != SslMode::VerifyFull);
+
+    Ok(Some(tls))
+}
+
+fn connect_postgres(connection: &PostgresConnection) -> Result<Connection, String> {
+    let options = &connection.options;
+    let host = if connection.is_unix_socket() {
+        Host::Unix(PathBuf::from(&connection.host))
+    } else {
+        Host::Tcp(connection.host.to_owned())
+    };
+
+    let mut builder = ConnectParams::builder();
+    builder
+        .port(connection.port)
+        .user(&connection.user, Some(&connection.pass))
+        .database(&connection.database)
+        .connect_timeout(Some(options.connect_timeout()))
+        .option("application_name", options.application_name());
+    if let Some(statement_timeout_ms) = options.statement_timeout_ms {
+        builder.option("options", &format!("-c statement_timeout={}", statement_timeout_ms));
+    }
+    let params = builder.build(host);
+
+    let tls = postgres_tls(options)?;
+    let tls_mode = match (options.sslmode(), tls.as_ref()) {
+        (SslMode::Allow, Some(tls)) | (SslMode::Prefer, Some(tls)) => TlsMode::Prefer(tls),
+        (_, Some(tls)) => TlsMode::Require(tls),
+        (_, None) => TlsMode::None,
+    };
+
+    Connection::connect(params, tls_mode)
+        .map_err(|err| err.to_string())
+}
+
+/// Connects and runs a trivial query, returns how long it took
+pub fn test_postgres(connection: &PostgresConnection) -> Result<Duration, String> {
+    let start = Instant::now();
+    let conn = connect_postgres(connection)?;
+    conn.batch_execute("SELECT 1")
+        .map_err(|err| err.to_string())?;
+
+    Ok(start.elapsed())
+}
+
+/// The databases on the server that the user may connect to, without the templates
+pub fn list_postgres_databases(connection: &PostgresConnection) -> Result<Vec<String>, String> {
+    let conn = connect_postgres(connection)?;
+    let rows = conn
+        .query("SELECT datname FROM pg_database \
+                WHERE NOT datistemplate AND datallowconn AND has_database_privilege(datname, 'CONNECT') \
+                ORDER BY datname", &[])
+        .map_err(|err| err.to_string())?;
+
+    Ok(rows
+        .iter()
+        .map(|row| row.get(0))
+        .collect())
+}
+
+/// Sends one command over RESP and reads the single line reply
+fn redis_command(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, args: &[&str]) -> Result<String, String> {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    stream.write_all(command.as_bytes())
+        .map_err(|err| err.to_string())?;
+
+    let mut reply = String::new();
+    reader.read_line(&mut reply)
+        .map_err(|err| err.to_string())?;
+
+    let reply = reply.trim_end();
+    if reply.starts_with('-') {
+        Err(reply[1..].to_string())
+    } else if reply.is_empty() {
+        Err("The connection was closed".to_string())
+    } else {
+        Ok(reply.to_string())
+    }
+}
+
+/// Connects, authenticates, selects the database and sends a `PING`, returns how long it took
+pub fn test_redis(host: &str, port: u16, pass: Option<&str>, database: u32) -> Result<Duration, String> {
+    let start = Instant::now();
+    let timeout = Duration::from_secs(CONNECT_TIMEOUT_SECS);
+    let address = (host, port)
+        .to_socket_addrs()
+        .map_err(|err| err.to_string())?
+        .next()
+        .ok_or_else(|| format!("Could not resolve {}", host))?;
+
+    let mut stream = TcpStream::connect_timeout(&address, timeout)
+        .map_err(|err| err.to_string())?;
+    stream.set_read_timeout(Some(timeout))
+        .map_err(|err| err.to_string())?;
+    let mut reader = BufReader::new(stream.try_clone().map_err(|err| err.to_string())?);
+
+    if let Some(pass) = pass {
+        redis_command(&mut stream, &mut reader, &["AUTH", pass])?;
+    }
+    if database != 0 {
+        redis_command(&mut stream, &mut reader, &["SELECT", &database.to_string()])?;
+    }
+    redis_command(&mut stream, &mut reader, &["PING"])?;
+
+    Ok(start.elapsed())
+}
+
+fn target_of(domain: &DomainInfo) -> String {
+    match domain {
+        DomainInfo::Postgres { host, port, user, database, options, .. } =>
+            format!("postgres://{}@{}:{}/{}?sslmode={}", user, host, port, database, options.sslmode()),
+        DomainInfo::Redis { host, port, database, .. } =>
+            format!("redis://{}:{}/{}", host, port, database),
+    }
+}
+
+/// Connects to the domain itself, ignoring any tunnel
+fn test_direct(domain: &DomainInfo) -> Result<Duration, String> {
+    match domain {
+        DomainInfo::Postgres { .. } => domain
+            .postgres_connection()
+            .ok_or_else(|| "Not a Postgres domain".to_string())
+            .and_then(|connection| test_postgres(&connection)),
+        DomainInfo::Redis { host, port, pass, database, .. } => {
+            let pass = pass.as_ref().map(|pass| pass.as_str());
+            test_redis(host, *port, pass, *database)
+        },
+    }
+}
+
+/// Tests the ssh hop first for domains behind a bastion, then the domain through the tunnel
+pub fn test_domain(name: &str, domain: &DomainInfo) -> ConnectionTest {
+    let target = target_of(domain);
+    let ssh_tunnel = match domain.ssh_tunnel() {
+        Some(ssh_tunnel) => ssh_tunnel,
+        None => return ConnectionTest::new(name, target, test_direct(domain)),
+    };
+
+    let start = Instant::now();
+    let opened = tunnel::pick_local_port(ssh_tunnel)
+        .and_then(|local_port| tunnel::open(ssh_tunnel, &tunnel::forward_target(domain), local_port));
+    let tunnel_latency = start.elapsed();
+
+    let mut test = match opened {
+        // the tunnel stays open until the test is done
+        Ok(ref opened) => ConnectionTest::new(name, target, test_direct(&tunnel::local_domain(domain, opened.local_port))),
+        Err(ref err) => ConnectionTest::new(name, target, Err(format!("ssh tunnel: {}", err))),
+    };
+    test.tunnel = Some(TunnelHop {
+        via: ssh_tunnel.to_string(),
+        ok: opened.is_ok(),
+        latency_ms: opened.as_ref().ok().map(|_| millis(tunnel_latency)),
+    });
+
+    test
+}
+
+fn list(config: &ConfigData, output: OutputFormat) -> Result<(), KakapoError> {
+    output::print(output, &config.domains, || wizard::print_domains(&config.domains))
+}
+
+fn test(config: &ConfigData, names: Vec<&str>, output: OutputFormat) -> Result<(), KakapoError> {
+    if let Some(name) = names.iter().find(|name| !config.domains.contains_key(**name)) {
+        return Err(KakapoError::Usage(format!("Unknown domain '{}'", name)));
+    }
+
+    let results: Vec<_> = config.domains
+        .iter()
+        .filter(|(name, _)| names.is_empty() || names.contains(&name.as_str()))
+        .map(|(name, domain)| test_domain(name, domain))
+        .collect();
+
+    output::print(output, &results, || {
+        for result in &results {
+            result.print_text();
+        }
+    })?;
+
+    let failed: Vec<_> = results
+        .iter()
+        .filter(|result| !result.ok)
+        .map(|result| result.name.as_str())
+        .collect();
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(KakapoError::ConnectionFailed {
+            target: failed.join(", "),
+            message: format!("{} of {} domains failed", failed.len(), results.len()),
+        })
+    }
+}
+
+/// Adds the domains to the config file the wizard edits, or to its profile when one is selected
+pub fn save_domains(source: &ConfigSource, domains: Vec<(String, DomainInfo)>) -> Result<ConfigData, KakapoError> {
+    let config = if source.path.exists() {
+        ConfigData::from_file(source.path.to_owned())?
+    } else {
+        ConfigData::default().with_path(source.path.to_owned())
+    };
+
+    let config = match source.profile {
+        Some(ref profile) => {
+            let mut view = config.profile_view(profile);
+            view.domains.extend(domains);
+            config.merge_profile(profile, view)
+        },
+        None => {
+            let mut config = config;
+            config.domains.extend(domains);
+            config
+        },
+    };
+
+    config.to_file()?;
+    Ok(config)
+}
+
+/// Whether any layer or profile already has a domain with this name
+fn is_taken(existing: &ConfigData, name: &str) -> bool {
+    existing.domains.contains_key(name) ||
+        existing.profiles.values().any(|profile| profile.domains.contains_key(name))
+}
+
+/// Prompts for whatever `--url` leaves out
+fn add(matches: &ArgMatches, source: &ConfigSource) -> Result<(), KakapoError> {
+    let existing = ConfigData::from_layers_or_default(&source.files, source.path.to_owned())?;
+    let name_taken = |name: &str| is_taken(&existing, name);
+
+    let name = match matches.value_of("name") {
+        Some(name) if name_taken(name) => return Err(KakapoError::Usage(format!("There already is a domain named '{}'", name))),
+        Some(name) => name.to_string(),
+        None => utils::unique_parrot_name(name_taken),
+    };
+
+    let url = match matches.value_of("url") {
+        Some(url) => ConnectionUrl::parse(url).map_err(KakapoError::Usage)?,
+        None => ConnectionUrl::default(),
+    };
+
+    let domain = connection::complete(&wizard::get_theme(), url)
+        .map_err(KakapoError::from_boxed)?
+        .into_domain();
+    let config = save_domains(source, vec![(name.to_owned(), domain)])?;
+
+    println!("Added the {} domain to {}",
+        RGB(131, 221, 2).bold().paint(name.as_str()),
+        RGB(131, 221, 2).bold().paint(config.config_path.to_string_lossy()));
+    Ok(())
+}
+
+fn import_candidates(matches: &ArgMatches) -> Result<Vec<Candidate>, KakapoError> {
+    let mut candidates = vec![];
+
+    if let Some(pg_service_path) = import::pg_service_path().filter(|path| path.exists()) {
+        let pgpass = match import::pgpass_path().filter(|path| path.exists()) {
+            Some(pgpass_path) => import::parse_pgpass(&fs::read_to_string(&pgpass_path)
+                .map_err(|err| KakapoError::io(&format!("Could not read {}", pgpass_path.display()), err))?),
+            None => vec![],
+        };
+        candidates.extend(import::from_pg_service(&pg_service_path, &pgpass)?);
+    }
+
+    let compose_file = match matches.value_of("compose-file") {
+        Some(compose_file) => Some(PathBuf::from(compose_file)),
+        None => import::find_compose_file(Path::new(".")),
+    };
+    if let Some(compose_file) = compose_file {
+        candidates.extend(import::from_compose(&compose_file)?);
+    }
+
+    Ok(candidates)
+}
+
+fn print_candidates(candidates: &[Candidate]) {
+    if candidates.is_empty() {
+        println!("Found no domains to import");
+    }
+
+    for candidate in candidates {
+        let missing_password = if candidate.missing_password { " (no password found)" } else { "" };
+        println!("{}=> {}{}",
+            RGB(131, 221, 2).bold().paint(format!("{: <16}", &candidate.name)),
+            &candidate.domain,
+            Yellow.paint(missing_password));
+        println!("    from {}", &candidate.source);
+    }
+}
+
+/// Offers the domains found in `~/.pg_service.conf` and `docker-compose.yml`, or takes all of them with `--yes`
+fn import(matches: &ArgMatches, source: &ConfigSource, output: OutputFormat) -> Result<(), KakapoError> {
+    let existing = ConfigData::from_layers_or_default(&source.files, source.path.to_owned())?;
+    let mut candidates = import_candidates(matches)?;
+    import::resolve_names(&mut candidates, |name| is_taken(&existing, name));
+
+    if matches.is_present("dry-run") {
+        return output::print(output, &candidates, || print_candidates(&candidates));
+    }
+
+    let theme = wizard::get_theme();
+    let mut imported: Vec<(String, DomainInfo)> = vec![];
+    for candidate in candidates {
+        if matches.is_present("yes") {
+            imported.push((candidate.name, candidate.domain));
+            continue;
+        }
+
+        let accept = Confirmation::with_theme(&theme)
+            .with_text(&format!("Import {} from {}?", &candidate.domain, &candidate.source))
+            .default(true)
+            .interact()?;
+        if !accept {
+            continue;
+        }
+
+        let name = loop {
+            let name: String = Input::with_theme(&theme)
+                .with_prompt("Name of the domain")
+                .default(candidate.name.to_owned())
+                .interact()?;
+
+            if is_taken(&existing, &name) || imported.iter().any(|(other, _)| *other == name) {
+                println!("{}", Yellow.paint(format!("There already is a domain named '{}'", name)));
+            } else {
+                break name;
+            }
+        };
+
+        let domain = match (candidate.domain, candidate.missing_password) {
+            (DomainInfo::Postgres { host, port, user, database, options, ssh_tunnel, .. }, true) => {
+                let pass = PasswordInput::with_theme(&theme)
+                    .with_prompt(&format!("Password of {}?", &user))
+                    .interact()?;
+                DomainInfo::Postgres { host, port, user, pass, database, options, ssh_tunnel }
+            },
+            (domain, _) => domain,
+        };
+
+        imported.push((name, domain));
+    }
+
+    if imported.is_empty() {
+        println!("Nothing imported");
+        return Ok(());
+    }
+
+    let names: Vec<_> = imported.iter().map(|(name, _)| name.to_owned()).collect();
+    let config = save_domains(source, imported)?;
+    println!("Imported {} into {}",
+        RGB(131, 221, 2).bold().paint(names.join(", ")),
+        RGB(131, 221, 2).bold().paint(config.config_path.to_string_lossy()));
+
+    Ok(())
+}
+
+/// `kakapo domain ...`
+pub fn run(matches: &ArgMatches, source: &ConfigSource, output: OutputFormat) -> Result<(), KakapoError> {
+    match matches.subcommand() {
+        ("add", Some(add_matches)) => return add(add_matches, source),
+        ("import", Some(import_matches)) => return import(import_matches, source, output),
+        _ => {},
+    }
+
+    let config = ConfigData::load(source)?;
+
+    match matches.subcommand() {
+        ("list", _) => list(&config, output),
+        ("test", Some(test_matches)) => {
+            let names = test_matches
+                .values_of("name")
+                .map(|names| names.collect())
+                .unwrap_or_default();
+            test(&config, names, output)
+        },
+        _ => Ok(()),
+    }
+}
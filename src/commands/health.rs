@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use ansi_term::Color::{Green, Red, Yellow};
+
+use commands;
+use commands::domain;
+use config::validate;
+use config::ConfigSource;
+use error::KakapoError;
+use output;
+use output::OutputFormat;
+use tls;
+use wizard::connection::PostgresConnection;
+use wizard;
+use wizard::ConfigData;
+
+/// Certificates expiring sooner than this are reported as a warning
+const TLS_WARNING_DAYS: i32 = 14;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum Status {
+    Ok,
+    Warning,
+    Failing,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub message: String,
+}
+
+impl Check {
+    fn new(name: &str, status: Status, message: &str) -> Self {
+        Check {
+            name: name.to_string(),
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Health {
+    pub status: Status,
+    pub checks: Vec<Check>,
+}
+
+fn check_server(config: &ConfigData) -> Option<Check> {
+    let server = config.server.as_ref()?;
+    let host = wizard::local_address(server.bind_address());
+    let name = format!("{}:{}", host, server.port);
+
+    let check = match commands::connect(host, server.port, Duration::from_secs(2)) {
+        Ok(_) => Check::new("server", Status::Ok, &format!("listening on {}", name)),
+        Err(err) => Check::new("server", Status::Failing, &format!("nothing is listening on {}: {}", name, err)),
+    };
+
+    Some(check)
+}
+
+fn check_tls(config: &ConfigData) -> Option<Check> {
+    let tls_info = config.server.as_ref()?.tls.as_ref()?;
+    let (cert_path, key_path) = tls_info.files();
+
+    let check = match tls::validate_certificate(cert_path, key_path) {
+        Ok(ref info) if info.days_left < TLS_WARNING_DAYS =>
+            Check::new("tls", Status::Warning, &format!("the certificate for {} expires in {} days", &info.common_name, info.days_left)),
+        Ok(info) =>
+            Check::new("tls", Status::Ok, &format!("the certificate for {} is valid for {} days", &info.common_name, info.days_left)),
+        Err(err) => Check::new("tls", Status::Failing, &err),
+    };
+
+    Some(check)
+}
+
+fn run_checks(source: &ConfigSource) -> Result<Vec<Check>, KakapoError> {
+    let validation = validate::validate_layers(source)?;
+    if !validation.valid {
+        let message = format!("{} issues, run `kakapo config validate` for details", validation.issues.len());
+        return Ok(vec![Check::new("config", Status::Failing, &message)]);
+    }
+
+    let config = ConfigData::load(source)?;
+    let mut checks = vec![Check::new("config", Status::Ok, "valid")];
+
+    if let Some(ref manager) = config.manager {
+        let result = domain::test_postgres(&PostgresConnection::from(manager));
+        checks.push(match result {
+            Ok(_) => Check::new("central database", Status::Ok, "reachable"),
+            Err(err) => Check::new("central database", Status::Failing, &err),
+        });
+    }
+
+    for (name, domain_info) in &config.domains {
+        let result = domain::test_domain(name, domain_info);
+        let name = format!("domain {}", name);
+        checks.push(match result.error {
+            None => Check::new(&name, Status::Ok, "reachable"),
+            Some(err) => Check::new(&name, Status::Failing, &err),
+        });
+    }
+
+    checks.extend(check_tls(&config));
+    checks.extend(check_server(&config));
+
+    Ok(checks)
+}
+
+/// `kakapo health`
+pub fn run(source: &ConfigSource, output: OutputFormat) -> Result<(), KakapoError> {
+    let checks = run_checks(source)?;
+    let health = Health {
+        status: checks.iter().map(|check| check.status).max().unwrap_or(Status::Ok),
+        checks,
+    };
+
+    output::print(output, &health, || {
+        for check in &health.checks {
+            let status = match check.status {
+                Status::Ok => Green.paint("ok     "),
+                Status::Warning => Yellow.paint("warning"),
+                Status::Failing => Red.paint("failing"),
+            };
+            println!("{} {: <24} {}", status, &check.name, &check.message);
+        }
+    })?;
+
+    if health.status == Status::Failing {
+        let failing: Vec<_> = health.checks
+            .iter()
+            .filter(|check| check.status == Status::Failing)
+            .map(|check| check.name.as_str())
+            .collect();
+        return Err(KakapoError::ConnectionFailed {
+            target: failing.join(", "),
+            message: format!("{} of {} health checks failing", failing.len(), health.checks.len()),
+        });
+    }
+
+    Ok(())
+}
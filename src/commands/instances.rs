@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+use std::process::Command;
+use std::str::from_utf8;
+use std::time::Duration;
+
+use ansi_term::Color::{Green, Red, Yellow, RGB};
+use clap::ArgMatches;
+
+use commands;
+use config;
+use config::instances;
+use error::KakapoError;
+use output;
+use output::OutputFormat;
+use wizard;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct InstanceStatus {
+    pub name: String,
+    pub unit: String,
+    pub port: u16,
+    /// What systemd says about the unit, `unknown` without systemd
+    pub status: String,
+    pub listening: bool,
+    /// The other instances configured with the same port
+    pub port_collisions: Vec<String>,
+}
+
+fn unit_status(unit: &str) -> String {
+    // `is-active` prints the state even when it exits with an error
+    Command::new("systemctl")
+        .args(&["is-active", unit])
+        .output()
+        .ok()
+        .and_then(|result| from_utf8(&result.stdout).ok().map(|status| status.trim().to_string()))
+        .filter(|status| !status.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn is_listening(bind: &str, port: u16) -> bool {
+    commands::connect(wizard::local_address(bind), port, Duration::from_secs(1)).is_ok()
+}
+
+fn instance_statuses() -> Result<Vec<InstanceStatus>, KakapoError> {
+    let ports = instances::ports()?;
+
+    let mut by_port: BTreeMap<u16, Vec<String>> = BTreeMap::new();
+    for (name, _, port) in &ports {
+        let name = instances::display_name(name.as_ref().map(|name| name.as_str()));
+        by_port.entry(*port).or_insert_with(Vec::new).push(name.to_string());
+    }
+
+    Ok(ports
+        .iter()
+        .map(|(name, bind, port)| {
+            let name = name.as_ref().map(|name| name.as_str());
+            let display_name = instances::display_name(name).to_string();
+            let unit = instances::unit_name(name);
+            let port_collisions = by_port[port]
+                .iter()
+                .filter(|other| **other != display_name)
+                .cloned()
+                .collect();
+
+            InstanceStatus {
+                status: unit_status(&unit),
+                listening: is_listening(bind, *port),
+                name: display_name,
+                unit,
+                port: *port,
+                port_collisions,
+            }
+        })
+        .collect())
+}
+
+fn list(output: OutputFormat) -> Result<(), KakapoError> {
+    let statuses = instance_statuses()?;
+
+    output::print(output, &statuses, || {
+        if statuses.is_empty() {
+            println!("No instances configured");
+        }
+
+        for instance in &statuses {
+            let status = match instance.status.as_str() {
+                "active" => Green.paint(format!("{: <10}", &instance.status)),
+                "failed" => Red.paint(format!("{: <10}", &instance.status)),
+                _ => Yellow.paint(format!("{: <10}", &instance.status)),
+            };
+            let listening = if instance.listening { "listening" } else { "" };
+            println!("{}=> port {: <6} {} {}",
+                RGB(131, 221, 2).bold().paint(format!("{: <16}", &instance.name)),
+                instance.port,
+                status,
+                listening);
+
+            if !instance.port_collisions.is_empty() {
+                println!("{}", Red.paint(format!("    port {} is also used by {}", instance.port, instance.port_collisions.join(", "))));
+            }
+        }
+    })?;
+
+    let colliding: Vec<_> = statuses
+        .iter()
+        .filter(|instance| !instance.port_collisions.is_empty())
+        .map(|instance| instance.name.to_owned())
+        .collect();
+    if !colliding.is_empty() {
+        let kakapo_home = config::get_kakapo_home()
+            .map_err(KakapoError::Other)?;
+        return Err(KakapoError::ConfigParse {
+            path: kakapo_home.join(instances::INSTANCES_DIR),
+            message: format!("Instances share a port: {}", colliding.join(", ")),
+        });
+    }
+
+    Ok(())
+}
+
+/// `kakapo instances ...`
+pub fn run(matches: &ArgMatches, output: OutputFormat) -> Result<(), KakapoError> {
+    match matches.subcommand() {
+        ("list", _) => list(output),
+        _ => Ok(()),
+    }
+}
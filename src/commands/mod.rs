@@ -0,0 +1,43 @@
+pub mod configuration;
+pub mod domain;
+pub mod health;
+pub mod instances;
+
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use clap::ArgMatches;
+
+use output::OutputFormat;
+
+/// Global flags can come after any of the subcommands, the one given last wins
+pub fn global_value<'a>(matches: &'a ArgMatches, name: &str) -> Option<&'a str> {
+    let mut value = matches.value_of(name);
+    let mut current = matches;
+
+    while let (_, Some(sub_matches)) = current.subcommand() {
+        if sub_matches.occurrences_of(name) > 0 {
+            value = sub_matches.value_of(name);
+        }
+        current = sub_matches;
+    }
+
+    value
+}
+
+/// Connects to the first address `host` resolves to
+pub fn connect(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    let address = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Could not resolve {}", host)))?;
+
+    TcpStream::connect_timeout(&address, timeout)
+}
+
+pub fn output_format(matches: &ArgMatches) -> OutputFormat {
+    global_value(matches, "output")
+        .and_then(|format| format.parse().ok())
+        .unwrap_or(OutputFormat::Text)
+}
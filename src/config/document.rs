@@ -0,0 +1,403 @@
+//! Writes a config back into the YAML document it was read from, changing only the values that changed.
+//! Comments, the order of the keys and keys Kakapo doesn't know about stay where they are.
+//! Only block mappings are edited key by key, anything else that changed is rewritten as a whole.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_yaml::{Mapping, Value};
+
+use config::keys::key_string;
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    /// Line of the key
+    line: usize,
+    /// First line after the entry's value
+    end: usize,
+    indent: usize,
+}
+
+struct Document {
+    lines: Vec<String>,
+}
+
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// Blank lines, comments and the document marker don't belong to any entry
+fn is_content(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty() && !line.starts_with('#') && line != "---"
+}
+
+/// The key of a `key: value` line, and the position of its colon
+fn split_entry(line: &str) -> Option<(String, usize)> {
+    let indent = indent_of(line);
+    let trimmed = &line[indent..];
+
+    if trimmed.starts_with("- ") {
+        return None;
+    }
+
+    let (key, after_key) = match trimmed.chars().next() {
+        Some(quote) if quote == '"' || quote == '\'' => {
+            let closing = trimmed[1..].find(quote)? + 1;
+            (trimmed[1..closing].to_string(), closing + 1)
+        },
+        _ => {
+            let colon = trimmed
+                .match_indices(':')
+                .map(|(idx, _)| idx)
+                .find(|idx| trimmed[idx + 1..].is_empty() || trimmed[idx + 1..].starts_with(' '))?;
+            (trimmed[..colon].trim_end().to_string(), colon)
+        },
+    };
+
+    if !trimmed[after_key..].starts_with(':') {
+        return None;
+    }
+
+    Some((key, indent + after_key))
+}
+
+/// Where the comment starts in the value part of a line, if there is one
+fn comment_start(value: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut after_space = true;
+
+    for (idx, c) in value.char_indices() {
+        match quote {
+            Some(q) => if c == q {
+                quote = None;
+            },
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '#' if after_space => return Some(idx),
+                _ => {},
+            },
+        }
+        after_space = c.is_whitespace();
+    }
+
+    None
+}
+
+fn to_yaml_lines<T: Serialize>(value: &T) -> Result<Vec<String>, String> {
+    let text = serde_yaml::to_string(value)
+        .map_err(|err| err.to_string())?;
+
+    Ok(text
+        .lines()
+        .filter(|line| *line != "---")
+        .map(|line| line.to_string())
+        .collect())
+}
+
+/// `key: value` as yaml lines, indented to fit in the document
+fn render_entry(key: &str, value: &Value, indent: usize) -> Result<Vec<String>, String> {
+    let mut mapping = Mapping::new();
+    mapping.insert(Value::String(key.to_string()), value.to_owned());
+
+    let padding = " ".repeat(indent);
+    Ok(to_yaml_lines(&mapping)?
+        .into_iter()
+        .map(|line| format!("{}{}", &padding, line))
+        .collect())
+}
+
+impl Document {
+    fn parse(text: &str) -> Self {
+        Document {
+            lines: text.lines().map(|line| line.to_string()).collect(),
+        }
+    }
+
+    fn to_string(&self) -> String {
+        let mut text = self.lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    fn block_end(&self, line: usize, indent: usize) -> usize {
+        let mut end = line + 1;
+
+        for idx in line + 1..self.lines.len() {
+            let current = &self.lines[idx];
+            if !is_content(current) {
+                continue;
+            }
+
+            // sequences may sit at the same indentation as their key
+            let is_nested = indent_of(current) > indent ||
+                (indent_of(current) == indent && current.trim_start().starts_with("- "));
+            if !is_nested {
+                break;
+            }
+            end = idx + 1;
+        }
+
+        end
+    }
+
+    /// The entries of the mapping between `start` and `end`
+    fn children(&self, start: usize, end: usize) -> Vec<(String, Entry)> {
+        let mut children = vec![];
+        let mut child_indent = None;
+        let mut idx = start;
+
+        while idx < end {
+            let line = &self.lines[idx];
+            if !is_content(line) {
+                idx += 1;
+                continue;
+            }
+
+            let indent = indent_of(line);
+            if *child_indent.get_or_insert(indent) != indent {
+                idx += 1;
+                continue;
+            }
+
+            match split_entry(line) {
+                Some((key, _)) => {
+                    let end = self.block_end(idx, indent);
+                    children.push((key, Entry { line: idx, end, indent }));
+                    idx = end;
+                },
+                None => idx += 1,
+            }
+        }
+
+        children
+    }
+
+    fn children_of(&self, entry: Option<Entry>) -> Vec<(String, Entry)> {
+        match entry {
+            Some(entry) => self.children(entry.line + 1, entry.end),
+            None => self.children(0, self.lines.len()),
+        }
+    }
+
+    fn find(&self, path: &[String]) -> Option<Entry> {
+        let mut entry = None;
+        for key in path {
+            entry = Some(self.children_of(entry)
+                .into_iter()
+                .find(|(child_key, _)| child_key == key)?
+                .1);
+        }
+
+        entry
+    }
+
+    /// The root, or an entry whose value is a block mapping that can be edited key by key
+    fn is_block_mapping(&self, entry: Option<Entry>) -> bool {
+        match entry {
+            Some(entry) => !self.children_of(Some(entry)).is_empty(),
+            None => true,
+        }
+    }
+
+    fn replace(&mut self, entry: Entry, key: &str, value: &Value) -> Result<(), String> {
+        let lines = render_entry(key, value, entry.indent)?;
+
+        // a scalar replacing a scalar keeps the original key and the comment after the value
+        if lines.len() == 1 && entry.end == entry.line + 1 {
+            let line = &self.lines[entry.line];
+            if let Some((_, colon)) = split_entry(line) {
+                let value_lines = to_yaml_lines(value)?;
+                let comment = comment_start(&line[colon + 1..])
+                    .map(|start| format!(" {}", &line[colon + 1 + start..]))
+                    .unwrap_or_default();
+                let updated = format!("{} {}{}", &line[..colon + 1], value_lines.join(" "), comment);
+                self.lines[entry.line] = updated;
+                return Ok(());
+            }
+        }
+
+        let _ = self.lines.splice(entry.line..entry.end, lines);
+        Ok(())
+    }
+
+    fn remove(&mut self, entry: Entry) {
+        let _ = self.lines.drain(entry.line..entry.end);
+    }
+
+    /// Adds the entry after the last key of its parent mapping
+    fn insert(&mut self, path: &[String], value: &Value) -> Result<(), String> {
+        let (key, parent_path) = path
+            .split_last()
+            .ok_or_else(|| "Can't insert the root".to_string())?;
+
+        let parent = if parent_path.is_empty() {
+            None
+        } else {
+            Some(self.find(parent_path).ok_or_else(|| format!("{} is missing", parent_path.join(".")))?)
+        };
+
+        let children = self.children_of(parent);
+        let (indent, position) = match (children.last(), parent) {
+            (Some((_, last)), Some(parent)) => (last.indent, parent.end),
+            (Some((_, last)), None) => (last.indent, last.end),
+            (None, None) => (0, self.lines.len()),
+            (None, Some(_)) => return Err(format!("{} is not a block mapping", parent_path.join("."))),
+        };
+
+        let lines = render_entry(key, value, indent)?;
+        let _ = self.lines.splice(position..position, lines);
+        Ok(())
+    }
+
+    fn update(&mut self, path: &mut Vec<String>, old: &Value, new: &Value) -> Result<(), String> {
+        if old == new {
+            return Ok(());
+        }
+
+        let entry = if path.is_empty() {
+            None
+        } else {
+            match self.find(path) {
+                Some(entry) => Some(entry),
+                // e.g. a section that only had its default value
+                None => return self.insert(path, new),
+            }
+        };
+
+        match (old, new, entry) {
+            (Value::Mapping(old_mapping), Value::Mapping(new_mapping), _) if self.is_block_mapping(entry) => {
+                for (key, old_value) in old_mapping {
+                    path.push(key_string(key));
+                    match new_mapping.get(key) {
+                        Some(new_value) => self.update(path, old_value, new_value)?,
+                        None => if let Some(entry) = self.find(path) {
+                            self.remove(entry);
+                        },
+                    }
+                    path.pop();
+                }
+
+                for (key, new_value) in new_mapping {
+                    if !old_mapping.contains_key(key) {
+                        path.push(key_string(key));
+                        self.insert(path, new_value)?;
+                        path.pop();
+                    }
+                }
+
+                Ok(())
+            },
+            (_, _, Some(entry)) => {
+                let key = path.last().cloned().unwrap_or_default();
+                self.replace(entry, &key, new)
+            },
+            (_, _, None) => Err("The document is not a mapping".to_string()),
+        }
+    }
+}
+
+/// Applies the difference between what `existing` parses to and `new` to the `existing` text. Fails if the result
+/// doesn't read back as `new`, in which case the caller should serialize `new` from scratch
+pub fn update_document<T>(existing: &str, new: &T) -> Result<String, String>
+    where
+        T: Serialize + DeserializeOwned,
+{
+    let old: T = serde_yaml::from_str(existing)
+        .map_err(|err| err.to_string())?;
+    let old = serde_yaml::to_value(&old)
+        .map_err(|err| err.to_string())?;
+    let new = serde_yaml::to_value(new)
+        .map_err(|err| err.to_string())?;
+
+    let mut document = Document::parse(existing);
+    document.update(&mut vec![], &old, &new)?;
+    let text = document.to_string();
+
+    let written: T = serde_yaml::from_str(&text)
+        .map_err(|err| err.to_string())?;
+    let written = serde_yaml::to_value(&written)
+        .map_err(|err| err.to_string())?;
+    if written != new {
+        return Err("The updated document doesn't match the configuration".to_string());
+    }
+
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml;
+    use serde_yaml::Value;
+
+    use super::update_document;
+
+    fn update(existing: &str, new: &str) -> String {
+        let new: Value = serde_yaml::from_str(new).unwrap();
+        update_document(existing, &new).unwrap()
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let existing = "# Kakapo\nserver:\n  host: localhost # the database\n  port: 1845\n";
+        let new = "server:\n  host: db\n  port: 1900\n";
+        assert_eq!(update(existing, new), "# Kakapo\nserver:\n  host: db # the database\n  port: 1900\n");
+    }
+
+    #[test]
+    fn keeps_unknown_keys() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Known {
+            name: String,
+            port: u16,
+        }
+
+        let existing = "name: kakapo\nplugins:\n  - custom\nport: 1845\n";
+        let new = Known { name: "kakapo".to_string(), port: 1900 };
+        assert_eq!(update_document(existing, &new).unwrap(), "name: kakapo\nplugins:\n  - custom\nport: 1900\n");
+    }
+
+    #[test]
+    fn inserts_and_removes_nested_entries() {
+        let existing = "\
+domains:
+  sirocco:
+    host: localhost # local
+    port: 5432
+  lisa:
+    host: db
+    port: 5433
+server:
+  port: 1845
+";
+        let new = "\
+domains:
+  sirocco:
+    host: localhost
+    port: 5432
+  ruth:
+    host: other
+    port: 5434
+server:
+  port: 1845
+";
+        assert_eq!(update(existing, new), new.replace("host: localhost", "host: localhost # local"));
+    }
+
+    #[test]
+    fn finds_quoted_keys() {
+        let existing = "domains:\n  \"my db\": # quoted\n    port: 5432\n  'other:db':\n    port: 5433\n";
+        let new = "domains:\n  my db:\n    port: 5432\n  other:db:\n    port: 6000\n";
+        assert_eq!(update(existing, new), "domains:\n  \"my db\": # quoted\n    port: 5432\n  'other:db':\n    port: 6000\n");
+    }
+
+    #[test]
+    fn replaces_list_values() {
+        let existing = "server:\n  hosts:\n  - a\n  - b\n  port: 1845 # keep\n";
+        let new = "server:\n  hosts:\n  - a\n  - c\n  port: 1845\n";
+        let updated = update(existing, new);
+
+        assert!(updated.contains("port: 1845 # keep"));
+        assert!(!updated.contains("- b"));
+        assert_eq!(serde_yaml::from_str::<Value>(&updated).unwrap(), serde_yaml::from_str::<Value>(new).unwrap());
+    }
+}
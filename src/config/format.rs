@@ -0,0 +1,65 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// The config file format, picked by the file's extension
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+pub const CONFIG_FORMATS: [&'static str; 3] = ["yaml", "toml", "json"];
+
+impl FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "toml" => Ok(ConfigFormat::Toml),
+            "json" => Ok(ConfigFormat::Json),
+            _ => Err(format!("unknown config format '{}', expected one of: {}", format, CONFIG_FORMATS.join(", "))),
+        }
+    }
+}
+
+impl ConfigFormat {
+    /// Files without an extension are read as yaml
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        match path.extension() {
+            Some(extension) => extension.to_string_lossy().parse(),
+            None => Ok(ConfigFormat::Yaml),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ConfigFormat::Yaml => "yaml",
+            ConfigFormat::Toml => "toml",
+            ConfigFormat::Json => "json",
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(&self, text: &str) -> Result<T, String> {
+        match self {
+            ConfigFormat::Yaml => serde_yaml::from_str(text).map_err(|err| err.to_string()),
+            ConfigFormat::Toml => toml::from_str(text).map_err(|err| err.to_string()),
+            ConfigFormat::Json => serde_json::from_str(text).map_err(|err| err.to_string()),
+        }
+    }
+
+    pub fn render<T: Serialize>(&self, value: &T) -> Result<String, String> {
+        match self {
+            ConfigFormat::Yaml => serde_yaml::to_string(value).map_err(|err| err.to_string()),
+            // going through a toml value puts the plain values before the tables, as toml requires
+            ConfigFormat::Toml => toml::Value::try_from(value)
+                .and_then(|value| toml::to_string_pretty(&value))
+                .map_err(|err| err.to_string()),
+            ConfigFormat::Json => serde_json::to_string_pretty(value).map_err(|err| err.to_string()),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! Several isolated Kakapo instances can run on one host, each one selected with `--instance NAME` or
+//! `KAKAPO_INSTANCE` and configured in `$KAKAPO_HOME/instances/NAME`. The instance's directory takes the place of
+//! the kakapo home when the config layers are read, the system and user wide layers are shared.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde_yaml;
+use serde_yaml::Value;
+
+use config;
+use config::layers;
+use error::KakapoError;
+use wizard::ServerInfo;
+
+pub const INSTANCES_DIR: &'static str = "instances";
+pub const DEFAULT_PORT: u16 = 1845;
+
+/// Instance names end up in directory and systemd unit names
+pub fn validate_name(name: String) -> Result<(), String> {
+    let is_valid = !name.is_empty() && name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if is_valid {
+        Ok(())
+    } else {
+        Err(format!("'{}' is not a valid instance name, use letters, digits, '-' and '_'", name))
+    }
+}
+
+pub fn get_instance_dir(kakapo_home: &Path, name: &str) -> PathBuf {
+    kakapo_home
+        .join(INSTANCES_DIR)
+        .join(name)
+}
+
+/// The instances that have a config file, sorted by name
+pub fn list() -> Result<Vec<String>, KakapoError> {
+    let kakapo_home = config::get_kakapo_home()
+        .map_err(KakapoError::Other)?;
+    let instances_dir = kakapo_home.join(INSTANCES_DIR);
+
+    let entries = match fs::read_dir(&instances_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(vec![]),
+    };
+
+    let mut names: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| config::find_config_file(&entry.path()).is_some())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| validate_name(name.to_owned()).is_ok())
+        .collect();
+    names.sort();
+
+    Ok(names)
+}
+
+/// The address and port the instance's server listens on, `None` being the default instance in the kakapo home
+pub fn listen_address(instance: Option<&str>) -> Result<(String, u16), KakapoError> {
+    // the daemon doesn't run in the project directory, its ./kakapo.yaml is not the instance's
+    let files: Vec<_> = layers::discover(None, instance)
+        .into_iter()
+        .filter(|file| file.as_path() != Path::new(layers::PROJECT_CONFIG))
+        .collect();
+    let layered = layers::load(&files)?;
+    let server = layered.value.get("server");
+
+    let port = server
+        .and_then(|server| server.get("port"))
+        .and_then(Value::as_u64)
+        .map(|port| port as u16)
+        .unwrap_or(DEFAULT_PORT);
+    // an incomplete server section still has a port
+    let bind = server
+        .and_then(|server| serde_yaml::from_value::<ServerInfo>(server.to_owned()).ok())
+        .map(|server| server.bind_address().to_string())
+        .unwrap_or_else(|| "127.0.0.1".to_string());
+
+    Ok((bind, port))
+}
+
+/// The configured instances, including the default one if it has a config, with the address and port they listen on
+pub fn ports() -> Result<Vec<(Option<String>, String, u16)>, KakapoError> {
+    let mut ports = vec![];
+
+    let has_default = config::get_kakapo_home()
+        .map(|kakapo_home| config::find_config_file(&kakapo_home).is_some())
+        .unwrap_or(false);
+    if has_default {
+        let (bind, port) = listen_address(None)?;
+        ports.push((None, bind, port));
+    }
+
+    for name in list()? {
+        let (bind, port) = listen_address(Some(&name))?;
+        ports.push((Some(name), bind, port));
+    }
+
+    Ok(ports)
+}
+
+/// The other instances that run on `port`
+pub fn port_collisions(instance: Option<&str>, port: u16) -> Result<Vec<Option<String>>, KakapoError> {
+    Ok(ports()?
+        .into_iter()
+        .filter(|(name, _, other_port)| name.as_ref().map(|name| name.as_str()) != instance && *other_port == port)
+        .map(|(name, _, _)| name)
+        .collect())
+}
+
+pub fn display_name(instance: Option<&str>) -> &str {
+    instance.unwrap_or("default")
+}
+
+/// The systemd unit running the instance, `kakapo@.service` is a template shared by the named instances
+pub fn unit_name(instance: Option<&str>) -> String {
+    match instance {
+        Some(instance) => format!("kakapo@{}.service", instance),
+        None => "kakapo.service".to_string(),
+    }
+}
@@ -0,0 +1,43 @@
+//! Dotted paths like `domains.sirocco.host` into the config's yaml value
+
+use serde_yaml;
+use serde_yaml::Value;
+
+/// The key as text, keys that aren't strings are written as yaml
+pub fn key_string(key: &Value) -> String {
+    match key {
+        Value::String(key) => key.to_owned(),
+        key => serde_yaml::to_string(key)
+            .map(|key| key.trim_start_matches("---").trim().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// `path.key`, or `key` at the root
+pub fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use super::{join, key_string};
+
+    #[test]
+    fn joins_paths() {
+        assert_eq!(join("", "server"), "server");
+        assert_eq!(join("server", "port"), "server.port");
+    }
+
+    #[test]
+    fn writes_keys_as_text() {
+        assert_eq!(key_string(&Value::String("host".to_string())), "host");
+        assert_eq!(key_string(&Value::Number(5432.into())), "5432");
+        assert_eq!(key_string(&Value::Bool(true)), "true");
+    }
+}
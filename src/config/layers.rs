@@ -0,0 +1,270 @@
+//! The config can be spread over several files, read in this order with the later ones taking precedence:
+//!
+//! 1. `/etc/kakapo/config.yaml`
+//! 2. `$XDG_CONFIG_HOME/kakapo/config.yaml`, `~/.config/kakapo/config.yaml` by default
+//! 3. `$KAKAPO_HOME/config.yaml`, `~/.kakapo/config.yaml` by default, or `$KAKAPO_HOME/instances/NAME/config.yaml`
+//!    when an instance is selected
+//! 4. `./kakapo.yaml`
+//! 5. the file given with `-c`
+//!
+//! The first three directories may also hold `config.toml` or `config.json` instead, and a `conf.d` directory whose
+//! `*.yaml` fragments are read in alphabetical order right after the directory's config file.
+//! Mappings are merged key by key, anything else is replaced by the later file.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::env::home_dir;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde_yaml::Value;
+
+use config;
+use config::format::ConfigFormat;
+use config::keys::{join, key_string};
+use error::KakapoError;
+
+pub const SYSTEM_CONFIG_DIR: &'static str = "/etc/kakapo";
+pub const PROJECT_CONFIG: &'static str = "kakapo.yaml";
+pub const FRAGMENTS_DIR: &'static str = "conf.d";
+
+/// The merged config, and the file each value came from
+#[derive(Clone, Debug)]
+pub struct Layered {
+    pub value: Value,
+    pub origins: BTreeMap<String, PathBuf>,
+}
+
+fn xdg_config_dir() -> Option<PathBuf> {
+    match env::var("XDG_CONFIG_HOME") {
+        Ok(ref dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => home_dir().map(|home| home.join(".config")),
+    }
+}
+
+fn fragments(dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir.join(FRAGMENTS_DIR)) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut fragments: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map(|extension| extension == "yaml" || extension == "yml").unwrap_or(false))
+        .collect();
+    fragments.sort();
+
+    fragments
+}
+
+fn push_dir(paths: &mut Vec<PathBuf>, dir: &Path) {
+    paths.extend(config::find_config_file(dir));
+    paths.extend(fragments(dir));
+}
+
+/// The config files that exist, lowest precedence first
+pub fn discover(explicit: Option<&Path>, instance: Option<&str>) -> Vec<PathBuf> {
+    let mut paths = vec![];
+
+    push_dir(&mut paths, Path::new(SYSTEM_CONFIG_DIR));
+    if let Some(xdg_config_dir) = xdg_config_dir() {
+        push_dir(&mut paths, &xdg_config_dir.join("kakapo"));
+    }
+    if let Ok(config_dir) = config::get_config_dir(instance) {
+        push_dir(&mut paths, &config_dir);
+    }
+    paths.push(PathBuf::from(PROJECT_CONFIG));
+    paths.extend(explicit.map(|path| path.to_owned()));
+
+    // the same file can show up twice, i.e. when KAKAPO_HOME is the XDG directory, it counts where it has the most weight
+    let canonical: Vec<_> = paths
+        .iter()
+        .map(|path| fs::canonicalize(path).ok())
+        .collect();
+
+    paths
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| match canonical[*idx] {
+            Some(ref path) => !canonical[idx + 1..].contains(&Some(path.to_owned())),
+            None => false,
+        })
+        .map(|(_, path)| path.to_owned())
+        .collect()
+}
+
+/// Reads one layer, any of the config formats works
+pub fn read_layer(path: &Path) -> Result<Value, KakapoError> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| KakapoError::io(&format!("Could not read {}", path.display()), err))?;
+    let format = ConfigFormat::from_path(path)
+        .map_err(|message| KakapoError::ConfigParse { path: path.to_owned(), message })?;
+
+    // serde_yaml fails on a yaml file without a document, i.e. a fragment that is empty or only has comments
+    let is_empty = text.lines().all(|line| line.trim().is_empty() || line.trim_start().starts_with('#'));
+    if format == ConfigFormat::Yaml && is_empty {
+        return Ok(Value::Null);
+    }
+
+    format.parse(&text)
+        .map_err(|message| KakapoError::ConfigParse { path: path.to_owned(), message })
+}
+
+fn record_origins(value: &Value, path: &str, source: &Path, origins: &mut BTreeMap<String, PathBuf>) {
+    match value {
+        Value::Mapping(mapping) if !mapping.is_empty() => {
+            for (key, value) in mapping {
+                record_origins(value, &join(path, &key_string(key)), source, origins);
+            }
+        },
+        _ => {
+            origins.insert(path.to_string(), source.to_owned());
+        },
+    }
+}
+
+/// Every value that isn't a mapping, by its dotted path
+pub fn leaves(value: &Value, path: &str, leaves: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Mapping(mapping) if !mapping.is_empty() => {
+            for (key, value) in mapping {
+                self::leaves(value, &join(path, &key_string(key)), leaves);
+            }
+        },
+        value => {
+            leaves.insert(path.to_string(), value.to_owned());
+        },
+    }
+}
+
+fn merge(base: &mut Value, layer: Value, path: &str, source: &Path, origins: &mut BTreeMap<String, PathBuf>) {
+    if let (Value::Mapping(base_mapping), Value::Mapping(layer_mapping)) = (&mut *base, &layer) {
+        for (key, value) in layer_mapping {
+            let child_path = join(path, &key_string(key));
+            match base_mapping.get_mut(key) {
+                Some(existing) => merge(existing, value.to_owned(), &child_path, source, origins),
+                None => {
+                    record_origins(value, &child_path, source, origins);
+                    base_mapping.insert(key.to_owned(), value.to_owned());
+                },
+            }
+        }
+        return;
+    }
+
+    // the whole subtree is replaced, so are its origins
+    let prefix = format!("{}.", path);
+    let replaced: Vec<_> = origins
+        .keys()
+        .filter(|key| *key == path || key.starts_with(&prefix))
+        .cloned()
+        .collect();
+    for key in replaced {
+        origins.remove(&key);
+    }
+
+    record_origins(&layer, path, source, origins);
+    *base = layer;
+}
+
+/// Reads and merges the layers, `files` comes from `discover`
+pub fn load(files: &[PathBuf]) -> Result<Layered, KakapoError> {
+    let mut layered = Layered {
+        value: Value::Mapping(Default::default()),
+        origins: BTreeMap::new(),
+    };
+
+    for file in files {
+        let layer = read_layer(file)?;
+        if let Value::Null = layer {
+            // an empty fragment
+            continue;
+        }
+        merge(&mut layered.value, layer, "", file, &mut layered.origins);
+    }
+
+    Ok(layered)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use serde_yaml::Value;
+
+    use super::{leaves, load, merge, Layered};
+
+    fn yaml(text: &str) -> Value {
+        serde_yaml::from_str(text).unwrap()
+    }
+
+    fn merged(layers: &[(&str, &str)]) -> Layered {
+        let mut layered = Layered {
+            value: Value::Mapping(Default::default()),
+            origins: BTreeMap::new(),
+        };
+        for &(source, text) in layers {
+            merge(&mut layered.value, yaml(text), "", Path::new(source), &mut layered.origins);
+        }
+
+        layered
+    }
+
+    #[test]
+    fn merges_mappings_key_by_key() {
+        let layered = merged(&[
+            ("system.yaml", "server: {host: kakapo.example.com, port: 1845}\nlogging: {level: info}"),
+            ("user.yaml", "server: {port: 8080}"),
+        ]);
+
+        assert_eq!(layered.value, yaml("server: {host: kakapo.example.com, port: 8080}\nlogging: {level: info}"));
+        assert_eq!(layered.origins["server.host"], PathBuf::from("system.yaml"));
+        assert_eq!(layered.origins["server.port"], PathBuf::from("user.yaml"));
+        assert_eq!(layered.origins["logging.level"], PathBuf::from("system.yaml"));
+    }
+
+    #[test]
+    fn replaces_everything_but_mappings() {
+        let layered = merged(&[
+            ("system.yaml", "logging: {targets: [stderr, file]}\nserver: {tls: {cert_path: a.pem, key_path: b.pem}}"),
+            ("user.yaml", "logging: {targets: [journald]}\nserver: {tls: ~}"),
+        ]);
+
+        assert_eq!(layered.value, yaml("logging: {targets: [journald]}\nserver: {tls: ~}"));
+        assert_eq!(layered.origins["logging.targets"], PathBuf::from("user.yaml"));
+        // the replaced subtree takes its origins with it
+        assert_eq!(layered.origins["server.tls"], PathBuf::from("user.yaml"));
+        assert!(!layered.origins.contains_key("server.tls.cert_path"));
+    }
+
+    #[test]
+    fn lists_the_leaves() {
+        let mut found = BTreeMap::new();
+        leaves(&yaml("domains: {sirocco: {host: db.internal, port: 5432}}\nadmin: {}"), "", &mut found);
+
+        let paths: Vec<_> = found.keys().map(|path| path.as_str()).collect();
+        assert_eq!(paths, vec!["admin", "domains.sirocco.host", "domains.sirocco.port"]);
+    }
+
+    #[test]
+    fn loads_the_files_in_order() {
+        let dir = env::temp_dir().join(format!("kakapo-layers-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = vec![dir.join("config.yaml"), dir.join("empty.yaml"), dir.join("kakapo.json")];
+        fs::write(&files[0], "server:\n  host: kakapo.example.com\n  port: 1845\n").unwrap();
+        fs::write(&files[1], "# nothing here yet\n").unwrap();
+        fs::write(&files[2], r#"{"server": {"port": 8080}}"#).unwrap();
+
+        let layered = load(&files);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let layered = layered.unwrap();
+        assert_eq!(layered.value, yaml("server: {host: kakapo.example.com, port: 8080}"));
+        assert_eq!(layered.origins["server.port"], files[2]);
+    }
+}
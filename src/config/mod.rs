@@ -1,3 +1,9 @@
+pub mod document;
+pub mod format;
+pub mod instances;
+pub mod keys;
+pub mod layers;
+pub mod validate;
 
 use std::path::PathBuf;
 use std::path::Path;
@@ -6,21 +12,61 @@ use std::env::home_dir;
 use std::env;
 
 pub const CONFIG_YAML: &'static str = "config.yaml";
+pub const CONFIG_TOML: &'static str = "config.toml";
+pub const CONFIG_JSON: &'static str = "config.json";
 
-pub fn get_config_path() -> Result<PathBuf, String> {
-    let mut config_path = PathBuf::new();
+/// Where the config is read from and written to, as chosen on the command line
+#[derive(Clone, Debug)]
+pub struct ConfigSource {
+    /// The layers, lowest precedence first
+    pub files: Vec<PathBuf>,
+    /// The file the wizard writes
+    pub path: PathBuf,
+    pub profile: Option<String>,
+    /// Selected with `--instance` or `KAKAPO_INSTANCE`, see `config::instances`
+    pub instance: Option<String>,
+}
+
+pub fn get_kakapo_home() -> Result<PathBuf, String> {
+    let mut kakapo_home = PathBuf::new();
     match env::var("KAKAPO_HOME") {
-        Ok(kakapo_home) => {
-            config_path.push(kakapo_home);
+        Ok(home) => {
+            kakapo_home.push(home);
         },
         Err(_) => {
             let home = home_dir()
                 .ok_or_else(|| "No home directory found, please specify an explicit 'KAKAPO_HOME' environment".to_string())?;
-            config_path.push(home);
-            config_path.push(".kakapo");
+            kakapo_home.push(home);
+            kakapo_home.push(".kakapo");
         },
     }
 
-    config_path.push(CONFIG_YAML);
+    Ok(kakapo_home)
+}
+
+/// The first of `config.yaml`, `config.toml` and `config.json` found in `dir`
+pub fn find_config_file(dir: &Path) -> Option<PathBuf> {
+    [CONFIG_YAML, CONFIG_TOML, CONFIG_JSON]
+        .iter()
+        .map(|file_name| dir.join(file_name))
+        .find(|config_path| config_path.exists())
+}
+
+/// The kakapo home, or the instance's directory in it
+pub fn get_config_dir(instance: Option<&str>) -> Result<PathBuf, String> {
+    let kakapo_home = get_kakapo_home()?;
+
+    Ok(match instance {
+        Some(instance) => instances::get_instance_dir(&kakapo_home, instance),
+        None => kakapo_home,
+    })
+}
+
+/// The config file in the kakapo home or the instance's directory, `config.yaml` if there is none yet
+pub fn get_config_path(instance: Option<&str>) -> Result<PathBuf, String> {
+    let config_dir = get_config_dir(instance)?;
+    let config_path = find_config_file(&config_dir)
+        .unwrap_or_else(|| config_dir.join(CONFIG_YAML));
+
     Ok(config_path)
-}
\ No newline at end of file
+}
@@ -0,0 +1,399 @@
+use std::env;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use log::LevelFilter;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::RootSchema;
+
+use config::layers;
+use config::ConfigSource;
+use error::KakapoError;
+use logging::LogTarget;
+use wizard::has_daemon;
+use wizard::ConfigData;
+use wizard::DomainInfo;
+use wizard::{PostgresOptions, SshTunnel, SslMode};
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Issue {
+    /// Dotted path of the offending value, e.g. `server.port`
+    pub field: String,
+    pub message: String,
+}
+
+impl Issue {
+    fn new(field: &str, message: &str) -> Self {
+        Issue {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Validation {
+    /// The layers that were validated, lowest precedence first
+    pub files: Vec<PathBuf>,
+    pub valid: bool,
+    pub issues: Vec<Issue>,
+}
+
+/// `prefix` is the dotted path of the section holding the host and the options
+fn check_postgres(prefix: &str, host: &str, options: &PostgresOptions, ssh_tunnel: Option<&SshTunnel>, issues: &mut Vec<Issue>) {
+    let field = |key: &str| format!("{}.{}", prefix, key);
+
+    // behind a tunnel the socket is on the bastion's side
+    if host.starts_with('/') && ssh_tunnel.is_none() && !Path::new(host).is_dir() {
+        issues.push(Issue::new(&field("host"), &format!("the unix socket directory {} does not exist", host)));
+    }
+    if let Some(ref ssl_root_cert) = options.ssl_root_cert {
+        if !ssl_root_cert.exists() {
+            issues.push(Issue::new(&field("options.ssl_root_cert"), &format!("{} does not exist", ssl_root_cert.display())));
+        }
+        if !options.sslmode().verifies_certificate() {
+            issues.push(Issue::new(&field("options.ssl_root_cert"), &format!("only used with verify-ca and verify-full, the sslmode is {}", options.sslmode())));
+        }
+    }
+    if options.connect_timeout_secs == Some(0) {
+        issues.push(Issue::new(&field("options.connect_timeout_secs"), "the connect timeout can't be 0"));
+    }
+    if options.pool_size == Some(0) {
+        issues.push(Issue::new(&field("options.pool_size"), "the pool needs at least one connection"));
+    }
+    if ssh_tunnel.is_some() && options.sslmode() == SslMode::VerifyFull {
+        issues.push(Issue::new(&field("options.sslmode"), "verify-full can't check the host name through an ssh tunnel, use verify-ca"));
+    }
+}
+
+fn check_ssh_tunnel(prefix: &str, tunnel: &SshTunnel, issues: &mut Vec<Issue>) {
+    let field = |key: &str| format!("{}.ssh_tunnel.{}", prefix, key);
+
+    if tunnel.host.is_empty() {
+        issues.push(Issue::new(&field("host"), "the bastion host is empty"));
+    }
+    if tunnel.port == 0 {
+        issues.push(Issue::new(&field("port"), "the port can't be 0"));
+    }
+    if tunnel.user.is_empty() {
+        issues.push(Issue::new(&field("user"), "the user is empty"));
+    }
+    if let Some(ref key_path) = tunnel.key_path {
+        if !key_path.exists() {
+            issues.push(Issue::new(&field("key_path"), &format!("{} does not exist", key_path.display())));
+        }
+    }
+    if tunnel.local_port == Some(0) {
+        issues.push(Issue::new(&field("local_port"), "leave the local port out to pick a free one"));
+    }
+}
+
+fn check_level(field: &str, level: &str, issues: &mut Vec<Issue>) {
+    if LevelFilter::from_str(level).is_err() {
+        issues.push(Issue::new(field, &format!("unknown log level '{}'", level)));
+    }
+}
+
+/// JSON Schema of the config file, generated from the same types the file is parsed into before `validate` runs
+pub fn schema() -> RootSchema {
+    SchemaGenerator::default().into_root_schema_for::<ConfigData>()
+}
+
+/// Without the `plugin-options` feature the plugins only take the host, port, user, password and database
+fn check_plugin_options(prefix: &str, domain: &DomainInfo, issues: &mut Vec<Issue>) {
+    let field = |key: &str| format!("{}.{}", prefix, key);
+    let needs_feature = |what: &str| format!("{} needs kakapo built with the plugin-options feature", what);
+
+    match domain {
+        DomainInfo::Postgres { host, options, ssh_tunnel, .. } => {
+            // through a tunnel the plugin connects to the local port
+            if host.starts_with('/') && ssh_tunnel.is_none() {
+                issues.push(Issue::new(&field("host"), &needs_feature("a unix socket")));
+            }
+            let set_options = [
+                ("sslmode", options.sslmode.is_some()),
+                ("ssl_root_cert", options.ssl_root_cert.is_some()),
+                ("connect_timeout_secs", options.connect_timeout_secs.is_some()),
+                ("statement_timeout_ms", options.statement_timeout_ms.is_some()),
+                ("pool_size", options.pool_size.is_some()),
+                ("application_name", options.application_name.is_some()),
+            ];
+            for &(option, is_set) in set_options.iter() {
+                if is_set {
+                    issues.push(Issue::new(&field(&format!("options.{}", option)), &needs_feature("the option")));
+                }
+            }
+        },
+        DomainInfo::Redis { pass, database, .. } => {
+            if pass.is_some() {
+                issues.push(Issue::new(&field("pass"), &needs_feature("a Redis password")));
+            }
+            if *database != 0 {
+                issues.push(Issue::new(&field("database"), &needs_feature("a Redis database other than 0")));
+            }
+        },
+    }
+}
+
+/// What the config asks for that this build of kakapo can't do, the server refuses to start with any of it
+pub fn unsupported(config: &ConfigData) -> Vec<Issue> {
+    let mut issues = vec![];
+
+    let has_tls = config.server.as_ref().map(|server| server.tls.is_some()).unwrap_or(false);
+    if has_tls && !cfg!(feature = "server-tls") {
+        issues.push(Issue::new("server.tls", "serving TLS needs kakapo built with the server-tls feature, \
+                                              leave it out and terminate TLS in a reverse proxy"));
+    }
+
+    if !cfg!(feature = "plugin-options") {
+        for (name, domain) in &config.domains {
+            check_plugin_options(&format!("domains.{}", name), domain, &mut issues);
+        }
+    }
+
+    issues
+}
+
+/// What the daemon can't do, systemd starts it without an ssh agent that could hold a tunnel's key
+pub fn daemon_issues(config: &ConfigData) -> Vec<Issue> {
+    config.domains
+        .iter()
+        .filter(|(_, domain)| domain.ssh_tunnel().map(|tunnel| tunnel.key_path.is_none()).unwrap_or(false))
+        .map(|(name, _)| Issue::new(&format!("domains.{}.ssh_tunnel.key_path", name),
+                                    "the daemon has no ssh agent, the tunnel needs a key without a passphrase"))
+        .collect()
+}
+
+/// Under systemd, or when a systemd unit is set up for the config's instance
+pub fn runs_as_daemon(config: &ConfigData) -> bool {
+    env::var_os("INVOCATION_ID").is_some() || has_daemon(config)
+}
+
+/// Checks what the types alone can't, i.e. missing sections, ports and files
+pub fn validate(config: &ConfigData) -> Vec<Issue> {
+    let mut issues = unsupported(config);
+    if runs_as_daemon(config) {
+        issues.extend(daemon_issues(config));
+    }
+
+    match config.manager {
+        Some(ref manager) => {
+            if manager.db_type != "postgres" {
+                issues.push(Issue::new("manager.type", &format!("unsupported database type '{}'", &manager.db_type)));
+            }
+            if manager.host.is_empty() {
+                issues.push(Issue::new("manager.host", "the host is empty"));
+            }
+            if manager.port == 0 {
+                issues.push(Issue::new("manager.port", "the port can't be 0"));
+            }
+            check_postgres("manager", &manager.host, &manager.options, None, &mut issues);
+        },
+        None => issues.push(Issue::new("manager", "no central database is configured")),
+    }
+
+    if let Some(ref server) = config.server {
+        if server.host.is_empty() {
+            issues.push(Issue::new("server.host", "the host is empty"));
+        }
+        if server.port == 0 {
+            issues.push(Issue::new("server.port", "the port can't be 0"));
+        }
+        if let Some(ref tls) = server.tls {
+            let (cert_path, key_path) = tls.files();
+            if !cert_path.exists() {
+                issues.push(Issue::new("server.tls.cert_path", &format!("{} does not exist", cert_path.display())));
+            }
+            if !key_path.exists() {
+                issues.push(Issue::new("server.tls.key_path", &format!("{} does not exist", key_path.display())));
+            }
+        }
+    }
+
+    for (name, domain) in &config.domains {
+        let field = |key: &str| format!("domains.{}.{}", name, key);
+        match domain {
+            DomainInfo::Postgres { host, port, .. } | DomainInfo::Redis { host, port, .. } => {
+                if host.is_empty() {
+                    issues.push(Issue::new(&field("host"), "the host is empty"));
+                }
+                if *port == 0 {
+                    issues.push(Issue::new(&field("port"), "the port can't be 0"));
+                }
+            },
+        }
+        if let DomainInfo::Postgres { host, options, ssh_tunnel, .. } = domain {
+            check_postgres(&format!("domains.{}", name), host, options, ssh_tunnel.as_ref(), &mut issues);
+        }
+        if let Some(tunnel) = domain.ssh_tunnel() {
+            check_ssh_tunnel(&format!("domains.{}", name), tunnel, &mut issues);
+        }
+    }
+
+    check_level("logging.level", &config.logging.level, &mut issues);
+    for (module, level) in &config.logging.modules {
+        check_level(&format!("logging.modules.{}", module), level, &mut issues);
+    }
+    if config.logging.target == LogTarget::File && config.logging.file.is_none() {
+        issues.push(Issue::new("logging.file", "the target is file, but no log file is configured"));
+    }
+
+    issues
+}
+
+/// Issues that only show up with the profile applied, reported under the profile
+fn validate_profile(config: &ConfigData, profile: &str, base_issues: &[Issue]) -> Vec<Issue> {
+    validate(&config.profile_view(profile))
+        .into_iter()
+        .filter(|issue| !base_issues.iter().any(|base_issue| base_issue.field == issue.field))
+        .map(|issue| Issue::new(&format!("profiles.{}.{}", profile, &issue.field), &issue.message))
+        .collect()
+}
+
+/// Parses every layer, then validates the merged config and each of its profiles, or only the selected profile.
+/// Files that don't parse are reported as issues, not as errors
+pub fn validate_layers(source: &ConfigSource) -> Result<Validation, KakapoError> {
+    let files = &source.files;
+    let mut issues = vec![];
+
+    if files.is_empty() {
+        issues.push(Issue::new("", "no configuration file found"));
+    }
+
+    for file in files {
+        match layers::read_layer(file) {
+            Ok(_) => {},
+            Err(KakapoError::ConfigParse { message, .. }) => issues.push(Issue::new(&file.to_string_lossy(), &message)),
+            Err(err) => return Err(err),
+        }
+    }
+
+    if issues.is_empty() {
+        // the merged config is saved nowhere, the path doesn't matter
+        match ConfigData::from_layers(files, PathBuf::new()) {
+            Ok(config) => {
+                let base_issues = validate(&config);
+                let profiles: Vec<_> = match source.profile {
+                    Some(ref profile) => {
+                        if !config.profiles.contains_key(profile) {
+                            issues.push(Issue::new("profiles", &format!("there is no profile '{}'", profile)));
+                        }
+                        vec![profile.to_owned()]
+                    },
+                    None => config.profiles.keys().cloned().collect(),
+                };
+
+                let profile_issues: Vec<_> = profiles
+                    .iter()
+                    .flat_map(|profile| validate_profile(&config, profile, &base_issues))
+                    .collect();
+                issues.extend(base_issues);
+                issues.extend(profile_issues);
+            },
+            Err(KakapoError::ConfigParse { message, .. }) => issues.push(Issue::new("", &message)),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(Validation {
+        files: files.to_vec(),
+        valid: issues.is_empty(),
+        issues,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use wizard::{ConfigData, DomainInfo, KnownHostsPolicy, PostgresOptions, SshTunnel, SslMode};
+
+    use super::{daemon_issues, unsupported};
+
+    fn with_domains(domains: Vec<(&str, DomainInfo)>) -> ConfigData {
+        let mut config = ConfigData::default();
+        config.domains = domains.into_iter().map(|(name, domain)| (name.to_string(), domain)).collect();
+        config
+    }
+
+    fn postgres(host: &str, options: PostgresOptions) -> DomainInfo {
+        DomainInfo::Postgres {
+            host: host.to_string(),
+            port: 5432,
+            user: "kakapo".to_string(),
+            pass: "secret".to_string(),
+            database: "kakapo".to_string(),
+            options,
+            ssh_tunnel: None,
+        }
+    }
+
+    fn redis(pass: Option<&str>, database: u32) -> DomainInfo {
+        DomainInfo::Redis {
+            host: "localhost".to_string(),
+            port: 6379,
+            pass: pass.map(|pass| pass.to_string()),
+            database,
+            ssh_tunnel: None,
+        }
+    }
+
+    #[test]
+    fn requires_a_key_for_the_daemon_tunnels() {
+        let tunnel = SshTunnel {
+            host: "bastion.example.com".to_string(),
+            port: 22,
+            user: "deploy".to_string(),
+            key_path: None,
+            known_hosts: KnownHostsPolicy::Strict,
+            local_port: None,
+        };
+        let mut with_agent = postgres("db.internal", PostgresOptions::default());
+        with_agent.set_ssh_tunnel(Some(tunnel.to_owned()));
+        let mut with_key = postgres("db.internal", PostgresOptions::default());
+        with_key.set_ssh_tunnel(Some(SshTunnel { key_path: Some(PathBuf::from("/home/kakapo/.ssh/id_ed25519")), ..tunnel }));
+        let config = with_domains(vec![
+            ("agent", with_agent),
+            ("key", with_key),
+            ("direct", postgres("db.internal", PostgresOptions::default())),
+        ]);
+
+        let fields: Vec<_> = daemon_issues(&config).into_iter().map(|issue| issue.field).collect();
+        assert_eq!(fields, vec!["domains.agent.ssh_tunnel.key_path"]);
+    }
+
+    #[test]
+    fn accepts_plain_domains() {
+        let config = with_domains(vec![
+            ("billing", postgres("db.internal", PostgresOptions::default())),
+            ("cache", redis(None, 0)),
+        ]);
+
+        assert!(unsupported(&config).is_empty());
+    }
+
+    #[test]
+    #[cfg(not(feature = "plugin-options"))]
+    fn reports_options_the_plugins_can_not_take() {
+        let options = PostgresOptions {
+            sslmode: Some(SslMode::Require),
+            pool_size: Some(4),
+            ..PostgresOptions::default()
+        };
+        let config = with_domains(vec![
+            ("billing", postgres("db.internal", options)),
+            ("local", postgres("/var/run/postgresql", PostgresOptions::default())),
+            ("cache", redis(Some("secret"), 2)),
+        ]);
+
+        let fields: Vec<_> = unsupported(&config).into_iter().map(|issue| issue.field).collect();
+        assert_eq!(fields, vec![
+            "domains.billing.options.sslmode",
+            "domains.billing.options.pool_size",
+            "domains.cache.pass",
+            "domains.cache.database",
+            "domains.local.host",
+        ]);
+    }
+}
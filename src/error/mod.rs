@@ -0,0 +1,160 @@
+//! Errors shared by the wizard and the server, each one with a hint and a stable process exit code.
+//!
+//! The meaning of each exit code is documented in the README.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use ansi_term::Color::{Red, Yellow};
+
+pub const EXIT_OTHER: i32 = 1;
+pub const EXIT_USAGE: i32 = 64;
+pub const EXIT_CONFIG_PARSE: i32 = 65;
+pub const EXIT_CONNECTION_FAILED: i32 = 69;
+pub const EXIT_EXTERNAL_COMMAND: i32 = 71;
+pub const EXIT_IO: i32 = 74;
+pub const EXIT_RESTART: i32 = 75;
+pub const EXIT_PERMISSION: i32 = 77;
+pub const EXIT_NOT_INTERACTIVE: i32 = 78;
+pub const EXIT_PROMPT_ABORTED: i32 = 130;
+
+#[derive(Debug)]
+pub enum KakapoError {
+    /// The file exists, but it doesn't parse or doesn't validate
+    ConfigParse { path: PathBuf, message: String },
+    Io { context: String, source: io::Error },
+    Permission { context: String },
+    /// The user aborted the wizard, or closed the input
+    PromptAborted,
+    ConnectionFailed { target: String, message: String },
+    ExternalCommand { command: String, message: String },
+    /// The wizard has to run but nobody is there to answer the prompts
+    NotInteractive { config_path: PathBuf },
+    Usage(String),
+    Other(String),
+}
+
+impl KakapoError {
+    pub fn io(context: &str, source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::PermissionDenied => KakapoError::Permission {
+                context: format!("{}: {}", context, source),
+            },
+            _ => KakapoError::Io { context: context.to_string(), source },
+        }
+    }
+
+    pub fn external_command(command: &str, message: &str) -> Self {
+        KakapoError::ExternalCommand {
+            command: command.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    /// Converts the errors returned by the wizard steps, which can be anything
+    pub fn from_boxed(err: Box<Error>) -> Self {
+        let err = match err.downcast::<KakapoError>() {
+            Ok(err) => return *err,
+            Err(err) => err,
+        };
+
+        match err.downcast::<io::Error>() {
+            Ok(err) => KakapoError::from(*err),
+            Err(err) => KakapoError::Other(err.to_string()),
+        }
+    }
+
+    /// What the user can do about it
+    pub fn hint(&self) -> Option<String> {
+        match self {
+            KakapoError::ConfigParse { .. } =>
+                Some("Fix the file, or run `kakapo configure --all` to write a new one".to_string()),
+            KakapoError::Io { .. } =>
+                Some("Check that the path exists and that the disk is not full".to_string()),
+            KakapoError::Permission { .. } =>
+                Some("Run the command as the owner of the file, or through sudo".to_string()),
+            KakapoError::PromptAborted =>
+                Some("Run the wizard again to pick up where you left off".to_string()),
+            KakapoError::ConnectionFailed { .. } =>
+                Some("Check that the server is running and that the host, port and credentials are correct".to_string()),
+            KakapoError::ExternalCommand { command, .. } => {
+                let program = command.split_whitespace().next().unwrap_or(command);
+                Some(format!("Make sure `{}` is installed and that you are allowed to run it", program))
+            },
+            KakapoError::NotInteractive { .. } =>
+                Some("Run `kakapo configure --all` in a terminal, or point to an existing configuration with -c or KAKAPO_HOME".to_string()),
+            KakapoError::Usage(_) =>
+                Some("Run `kakapo --help` for the available options".to_string()),
+            KakapoError::Other(_) => None,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            KakapoError::ConfigParse { .. } => EXIT_CONFIG_PARSE,
+            KakapoError::Io { .. } => EXIT_IO,
+            KakapoError::Permission { .. } => EXIT_PERMISSION,
+            KakapoError::PromptAborted => EXIT_PROMPT_ABORTED,
+            KakapoError::ConnectionFailed { .. } => EXIT_CONNECTION_FAILED,
+            KakapoError::ExternalCommand { .. } => EXIT_EXTERNAL_COMMAND,
+            KakapoError::NotInteractive { .. } => EXIT_NOT_INTERACTIVE,
+            KakapoError::Usage(_) => EXIT_USAGE,
+            KakapoError::Other(_) => EXIT_OTHER,
+        }
+    }
+
+    /// Prints the error and its hint to stderr
+    pub fn print(&self) {
+        eprintln!("{}", Red.bold().paint(self.to_string()));
+        if let Some(hint) = self.hint() {
+            eprintln!("{} {}", Yellow.paint("hint:"), hint);
+        }
+    }
+
+    /// Prints the error and exits with its exit code
+    pub fn exit(&self) -> ! {
+        self.print();
+        ::std::process::exit(self.exit_code())
+    }
+}
+
+impl fmt::Display for KakapoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KakapoError::ConfigParse { path, message } =>
+                write!(f, "Invalid configuration {}: {}", path.display(), message),
+            KakapoError::Io { context, source } if context.is_empty() => write!(f, "{}", source),
+            KakapoError::Io { context, source } => write!(f, "{}: {}", context, source),
+            KakapoError::Permission { context } => write!(f, "Permission denied: {}", context),
+            KakapoError::PromptAborted =>
+                write!(f, "Configuration aborted"),
+            KakapoError::ConnectionFailed { target, message } =>
+                write!(f, "Could not connect to {}: {}", target, message),
+            KakapoError::ExternalCommand { command, message } =>
+                write!(f, "`{}` failed: {}", command, message),
+            KakapoError::NotInteractive { config_path } =>
+                write!(f, "No configuration found at {} and no terminal to start the configuration wizard", config_path.display()),
+            KakapoError::Usage(message) => write!(f, "{}", message),
+            KakapoError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for KakapoError {
+    fn description(&self) -> &str {
+        "kakapo error"
+    }
+}
+
+impl From<io::Error> for KakapoError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => KakapoError::Permission { context: err.to_string() },
+            // dialoguer and the line-based prompts report ctrl-c and a closed stdin this way
+            io::ErrorKind::Interrupted | io::ErrorKind::UnexpectedEof => KakapoError::PromptAborted,
+            _ => KakapoError::Io { context: String::new(), source: err },
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use logging::LogFileConfig;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const BYTES_PER_MB: u64 = 1024 * 1024;
+
+/// Log file that is moved aside once it gets too big or too old, keeping at most `max_files` old logs
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    max_files: usize,
+    compress: bool,
+    file: File,
+    size: u64,
+    opened_at: SystemTime,
+}
+
+fn open_append(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
+impl RotatingFile {
+    pub fn open(config: &LogFileConfig) -> io::Result<Self> {
+        let file = open_append(&config.path)?;
+        let metadata = file.metadata()?;
+        let opened_at = metadata.created()
+            .or_else(|_| metadata.modified())
+            .unwrap_or_else(|_| SystemTime::now());
+
+        Ok(RotatingFile {
+            path: config.path.to_owned(),
+            max_size: config.max_size_mb.map(|size| size * BYTES_PER_MB),
+            max_age: config.max_age_days.map(|days| Duration::from_secs(days * SECONDS_PER_DAY)),
+            max_files: config.max_files,
+            compress: config.compress,
+            file,
+            size: metadata.len(),
+            opened_at,
+        })
+    }
+
+    fn rotated_path(&self, idx: usize, compressed: bool) -> PathBuf {
+        let suffix = if compressed { ".gz" } else { "" };
+        PathBuf::from(format!("{}.{}{}", self.path.display(), idx, suffix))
+    }
+
+    fn needs_rotation(&self, next_line: usize) -> bool {
+        let too_big = self.max_size
+            .map(|max_size| self.size > 0 && self.size + next_line as u64 > max_size)
+            .unwrap_or(false);
+
+        let too_old = self.max_age
+            .and_then(|max_age| {
+                SystemTime::now()
+                    .duration_since(self.opened_at)
+                    .ok()
+                    .map(|age| age >= max_age)
+            })
+            .unwrap_or(false);
+
+        too_big || too_old
+    }
+
+    fn compress_file(from: &PathBuf, to: &PathBuf) -> io::Result<()> {
+        let mut source = File::open(from)?;
+        let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+        io::copy(&mut source, &mut encoder)?;
+        encoder.finish()?;
+        fs::remove_file(from)
+    }
+
+    /// `log` becomes `log.1`, `log.1` becomes `log.2`, ... and the oldest one is dropped
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for compressed in &[false, true] {
+                let oldest = self.rotated_path(self.max_files, *compressed);
+                if oldest.exists() {
+                    fs::remove_file(&oldest)?;
+                }
+
+                for idx in (1..self.max_files).rev() {
+                    let from = self.rotated_path(idx, *compressed);
+                    if from.exists() {
+                        fs::rename(&from, self.rotated_path(idx + 1, *compressed))?;
+                    }
+                }
+            }
+
+            let rotated = self.rotated_path(1, false);
+            fs::rename(&self.path, &rotated)?;
+
+            if self.compress {
+                RotatingFile::compress_file(&rotated, &self.rotated_path(1, true))?;
+            }
+        }
+
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.opened_at = SystemTime::now();
+
+        Ok(())
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let line_size = line.len() + 1;
+        if self.needs_rotation(line_size) {
+            if let Err(err) = self.rotate() {
+                eprintln!("Could not rotate log file {}: {}", self.path.display(), err);
+            }
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += line_size as u64;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
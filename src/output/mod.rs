@@ -0,0 +1,86 @@
+use std::str::FromStr;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use error::KakapoError;
+
+pub const OUTPUT_FORMATS: [&'static str; 3] = ["text", "json", "yaml"];
+
+pub const REDACTED: &'static str = "*****";
+
+/// How informational commands print their results, `text` is for humans, the others for scripts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Yaml,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "yaml" => Ok(OutputFormat::Yaml),
+            _ => Err(format!("unknown output format '{}', expected one of: {}", format, OUTPUT_FORMATS.join(", "))),
+        }
+    }
+}
+
+pub fn is_secret(key: &str) -> bool {
+    key == "pass" || key == "pass_hash" || key.ends_with("password") || key.ends_with("secret")
+}
+
+fn redact_value(value: Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let object = object
+                .into_iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        Value::String(ref secret) if is_secret(&key) && !secret.is_empty() =>
+                            Value::String(REDACTED.to_string()),
+                        value => redact_value(value),
+                    };
+                    (key, value)
+                })
+                .collect();
+            Value::Object(object)
+        },
+        Value::Array(array) => Value::Array(array.into_iter().map(redact_value).collect()),
+        value => value,
+    }
+}
+
+/// The value as json, with the passwords and secrets replaced
+pub fn redacted<T: Serialize>(value: &T) -> Result<Value, KakapoError> {
+    serde_json::to_value(value)
+        .map(redact_value)
+        .map_err(|err| KakapoError::Other(format!("Could not serialize the output: {}", err)))
+}
+
+/// Prints `value` as json or yaml, or calls `text` to print it for humans. Secrets are redacted either way
+pub fn print<T, F>(format: OutputFormat, value: &T, text: F) -> Result<(), KakapoError>
+    where
+        T: Serialize,
+        F: FnOnce(),
+{
+    match format {
+        OutputFormat::Text => text(),
+        OutputFormat::Json => {
+            let output = serde_json::to_string_pretty(&redacted(value)?)
+                .map_err(|err| KakapoError::Other(format!("Could not serialize the output: {}", err)))?;
+            println!("{}", output);
+        },
+        OutputFormat::Yaml => {
+            let output = serde_yaml::to_string(&redacted(value)?)
+                .map_err(|err| KakapoError::Other(format!("Could not serialize the output: {}", err)))?;
+            println!("{}", output);
+        },
+    }
+
+    Ok(())
+}
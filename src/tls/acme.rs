@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::env;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use acme_lib::{Directory, DirectoryUrl};
+use acme_lib::create_p384_key;
+use acme_lib::persist::FilePersist;
+
+use error::EXIT_RESTART;
+use tls::{write_pem, CERT_PEM, KEY_PEM};
+
+pub const LETS_ENCRYPT_DIRECTORY: &'static str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Directory inside the tls directory where the account key and the issued certificates are kept
+const ACME_DIR: &'static str = "acme";
+const CHALLENGE_PATH: &'static str = "/.well-known/acme-challenge/";
+
+const RENEW_BEFORE_DAYS: i64 = 30;
+const RENEWAL_CHECK_INTERVAL_SECS: u64 = 12 * 60 * 60;
+const VALIDATION_POLL_MS: u64 = 5000;
+/// Ports below this one need root or `CAP_NET_BIND_SERVICE`
+const FIRST_UNPRIVILEGED_PORT: u16 = 1024;
+
+#[derive(Clone, Debug)]
+pub struct AcmeSettings {
+    pub host: String,
+    pub email: String,
+    pub directory_url: String,
+    pub http_port: u16,
+    pub directory_ca: Option<PathBuf>,
+    pub tls_dir: PathBuf,
+}
+
+/// Whether answering the challenge needs `CAP_NET_BIND_SERVICE` when not running as root
+pub fn needs_bind_capability(http_port: u16) -> bool {
+    http_port < FIRST_UNPRIVILEGED_PORT
+}
+
+/// Serves the HTTP-01 challenge tokens while an order is being validated
+struct ChallengeServer {
+    port: u16,
+    proofs: Arc<Mutex<HashMap<String, String>>>,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ChallengeServer {
+    fn start(port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind(("0.0.0.0", port))
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::PermissionDenied if needs_bind_capability(port) => format!(
+                    "Could not listen on port {} for the HTTP challenge: {}, run as root or grant CAP_NET_BIND_SERVICE \
+                     (AmbientCapabilities= in the systemd unit)", port, err),
+                _ => format!("Could not listen on port {} for the HTTP challenge: {}", port, err),
+            })?;
+        let proofs: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread_proofs = proofs.clone();
+        let thread_running = running.clone();
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if !thread_running.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                if let Ok(stream) = stream {
+                    if let Err(err) = respond(stream, &thread_proofs) {
+                        warn!("Could not answer ACME challenge request: {}", &err);
+                    }
+                }
+            }
+        });
+
+        Ok(ChallengeServer {
+            port,
+            proofs,
+            running,
+            handle: Some(handle),
+        })
+    }
+
+    fn add_proof(&self, token: &str, proof: &str) {
+        if let Ok(mut proofs) = self.proofs.lock() {
+            proofs.insert(token.to_string(), proof.to_string());
+        }
+    }
+}
+
+impl Drop for ChallengeServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        // wake up the listener so that it can see it has to stop
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn respond(stream: TcpStream, proofs: &Mutex<HashMap<String, String>>) -> io::Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let path = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("");
+
+    let proof = if path.starts_with(CHALLENGE_PATH) {
+        let token = &path[CHALLENGE_PATH.len()..];
+        proofs.lock()
+            .ok()
+            .and_then(|proofs| proofs.get(token).cloned())
+    } else {
+        None
+    };
+
+    let mut stream = stream;
+    match proof {
+        Some(proof) => write!(stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            proof.len(), proof),
+        None => write!(stream,
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+    }
+}
+
+/// The ACME client verifies the directory with the system's TLS settings. It changes the environment, so it has to be
+/// called before any thread is started
+pub fn trust_directory_ca(directory_ca: &Path) {
+    env::set_var("SSL_CERT_FILE", directory_ca);
+}
+
+fn directory(settings: &AcmeSettings) -> Result<Directory<FilePersist>, String> {
+    let acme_dir = settings.tls_dir.join(ACME_DIR);
+    fs::create_dir_all(&acme_dir)
+        .map_err(|err| format!("Could not create {}: {}", acme_dir.display(), err))?;
+
+    let url = if settings.directory_url == LETS_ENCRYPT_DIRECTORY {
+        DirectoryUrl::LetsEncrypt
+    } else {
+        DirectoryUrl::Other(&settings.directory_url)
+    };
+
+    Directory::from_url(FilePersist::new(acme_dir), url)
+        .map_err(|err| format!("Could not reach the ACME directory {}: {}", &settings.directory_url, err))
+}
+
+/// Number of days the current certificate is still valid for, if there is one
+pub fn days_left(settings: &AcmeSettings) -> Result<Option<i64>, String> {
+    let account = directory(settings)?
+        .account(&settings.email)
+        .map_err(|err| err.to_string())?;
+
+    let certificate = account.certificate(&settings.host)
+        .map_err(|err| err.to_string())?;
+
+    Ok(certificate.map(|certificate| certificate.valid_days_left()))
+}
+
+/// Orders a new certificate for the host, answering the HTTP-01 challenges on `http_port`,
+/// returns the paths to the certificate and the key
+pub fn obtain_certificate(settings: &AcmeSettings) -> Result<(PathBuf, PathBuf), String> {
+    let account = directory(settings)?
+        .account(&settings.email)
+        .map_err(|err| err.to_string())?;
+
+    let mut new_order = account.new_order(&settings.host, &[])
+        .map_err(|err| err.to_string())?;
+
+    let challenge_server = ChallengeServer::start(settings.http_port)?;
+
+    let csr_order = loop {
+        if let Some(csr_order) = new_order.confirm_validations() {
+            break csr_order;
+        }
+
+        let authorizations = new_order.authorizations()
+            .map_err(|err| err.to_string())?;
+
+        for authorization in &authorizations {
+            let challenge = authorization.http_challenge();
+            challenge_server.add_proof(&challenge.http_token(), &challenge.http_proof());
+            challenge.validate(VALIDATION_POLL_MS)
+                .map_err(|err| format!("Validation for {} failed: {}", &settings.host, err))?;
+        }
+
+        new_order.refresh()
+            .map_err(|err| err.to_string())?;
+    };
+
+    drop(challenge_server);
+
+    let certificate = csr_order.finalize_pkey(create_p384_key(), VALIDATION_POLL_MS)
+        .and_then(|cert_order| cert_order.download_and_save_cert())
+        .map_err(|err| err.to_string())?;
+
+    let cert_path = settings.tls_dir.join(CERT_PEM);
+    let key_path = settings.tls_dir.join(KEY_PEM);
+
+    write_pem(&cert_path, certificate.certificate().as_bytes(), 0o644)?;
+    write_pem(&key_path, certificate.private_key().as_bytes(), 0o600)?;
+
+    Ok((cert_path, key_path))
+}
+
+/// The server only reads the certificate when it starts. Under systemd (`Restart=on-failure`) the server is asked to
+/// exit through `shutdown` so it comes back with the new one, otherwise the operator has to restart it
+fn restart_with_new_certificate(settings: &AcmeSettings, shutdown: &Sender<i32>) {
+    if env::var_os("INVOCATION_ID").is_some() {
+        info!("Exiting so systemd restarts the server with the renewed certificate for {}", &settings.host);
+        let _ = shutdown.send(EXIT_RESTART);
+        return;
+    }
+
+    warn!("Restart the server to use the renewed certificate for {}", &settings.host);
+}
+
+/// Renews the certificate in the background once it gets close to expiring, sends the exit code on `shutdown` when the
+/// server has to restart
+pub fn start_renewal(settings: AcmeSettings, shutdown: Sender<i32>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            let renew = match days_left(&settings) {
+                Ok(Some(days)) => days < RENEW_BEFORE_DAYS,
+                Ok(None) => true,
+                Err(err) => {
+                    warn!("Could not check the certificate for {}: {}", &settings.host, &err);
+                    false
+                },
+            };
+
+            if renew {
+                match obtain_certificate(&settings) {
+                    Ok((cert_path, _)) => {
+                        info!("Renewed the certificate for {} in {}", &settings.host, cert_path.display());
+                        restart_with_new_certificate(&settings, &shutdown);
+                    },
+                    Err(err) => error!("Could not renew the certificate for {}: {}", &settings.host, &err),
+                }
+            }
+
+            thread::sleep(Duration::from_secs(RENEWAL_CHECK_INTERVAL_SECS));
+        }
+    })
+}
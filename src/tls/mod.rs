@@ -0,0 +1,146 @@
+pub mod acme;
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::x509::{X509, X509NameBuilder};
+use openssl::x509::extension::SubjectAlternativeName;
+
+pub const TLS_DIR: &'static str = "tls";
+pub const CERT_PEM: &'static str = "cert.pem";
+pub const KEY_PEM: &'static str = "key.pem";
+
+/// Number of days a self-signed certificate is valid for
+const SELF_SIGNED_DAYS: u32 = 365;
+
+#[derive(Clone, Debug)]
+pub struct CertificateInfo {
+    pub common_name: String,
+    pub days_left: i32,
+}
+
+/// Checks that the certificate and the key parse, that they belong together and that the certificate is currently valid
+pub fn validate_certificate(cert_path: &Path, key_path: &Path) -> Result<CertificateInfo, String> {
+    let cert_pem = fs::read(cert_path)
+        .map_err(|err| format!("Could not read {}: {}", cert_path.display(), err))?;
+    let key_pem = fs::read(key_path)
+        .map_err(|err| format!("Could not read {}: {}", key_path.display(), err))?;
+
+    let cert = X509::from_pem(&cert_pem)
+        .map_err(|_| format!("{} is not a PEM encoded certificate", cert_path.display()))?;
+    let key = PKey::private_key_from_pem(&key_pem)
+        .map_err(|_| format!("{} is not a PEM encoded private key", key_path.display()))?;
+
+    let cert_key = cert.public_key()
+        .map_err(|err| err.to_string())?;
+    if !cert_key.public_eq(&key) {
+        return Err(format!("{} does not match the key {}", cert_path.display(), key_path.display()));
+    }
+
+    let now = Asn1Time::days_from_now(0)
+        .map_err(|err| err.to_string())?;
+
+    let since_start = cert.not_before().diff(&now)
+        .map_err(|err| err.to_string())?;
+    if since_start.days < 0 || since_start.secs < 0 {
+        return Err(format!("{} is not valid before {}", cert_path.display(), cert.not_before()));
+    }
+
+    let until_end = now.diff(cert.not_after())
+        .map_err(|err| err.to_string())?;
+    if until_end.days < 0 || until_end.secs < 0 || (until_end.days == 0 && until_end.secs == 0) {
+        return Err(format!("{} expired on {}", cert_path.display(), cert.not_after()));
+    }
+
+    let common_name = cert.subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map(|name| name.to_string())
+        .unwrap_or_default();
+
+    Ok(CertificateInfo {
+        common_name,
+        days_left: until_end.days,
+    })
+}
+
+fn build_self_signed(host: &str, key: &PKey<Private>) -> Result<X509, ErrorStack> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", host)?;
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(key)?;
+    builder.set_not_before(&Asn1Time::days_from_now(0)?)?;
+    builder.set_not_after(&Asn1Time::days_from_now(SELF_SIGNED_DAYS)?)?;
+
+    let alt_name = SubjectAlternativeName::new()
+        .dns(host)
+        .build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(alt_name)?;
+
+    builder.sign(key, MessageDigest::sha256())?;
+
+    Ok(builder.build())
+}
+
+/// Writes a pem file, keys are only readable by the owner
+pub fn write_pem(path: &Path, pem: &[u8], mode: u32) -> Result<(), String> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .map_err(|err| format!("Could not write {}: {}", path.display(), err))?;
+
+    file.write_all(pem)
+        .map_err(|err| format!("Could not write {}: {}", path.display(), err))
+}
+
+/// Generates a new key pair and a self-signed certificate for `host` inside `directory`,
+/// returns the paths to the certificate and the key
+pub fn generate_self_signed(host: &str, directory: &Path) -> Result<(PathBuf, PathBuf), String> {
+    let key = Rsa::generate(2048)
+        .and_then(PKey::from_rsa)
+        .map_err(|err| err.to_string())?;
+
+    let cert = build_self_signed(host, &key)
+        .map_err(|err| err.to_string())?;
+
+    let cert_pem = cert.to_pem()
+        .map_err(|err| err.to_string())?;
+    let key_pem = key.private_key_to_pem_pkcs8()
+        .map_err(|err| err.to_string())?;
+
+    fs::create_dir_all(directory)
+        .map_err(|err| format!("Could not create {}: {}", directory.display(), err))?;
+
+    let cert_path = directory.join(CERT_PEM);
+    let key_path = directory.join(KEY_PEM);
+
+    write_pem(&cert_path, &cert_pem, 0o644)?;
+    write_pem(&key_path, &key_pem, 0o600)?;
+
+    Ok((cert_path, key_path))
+}
@@ -0,0 +1,407 @@
+//! Forwards a local port to a domain behind a bastion host with the system's `ssh`, so the domain is reached on
+//! 127.0.0.1 and nothing else has to know about the tunnel
+
+use std::io::Read;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use error::KakapoError;
+use wizard::{DomainInfo, KnownHostsPolicy, SshTunnel};
+
+pub const LOCAL_HOST: &'static str = "127.0.0.1";
+
+/// How long ssh gets to log in and open the forward
+const OPEN_TIMEOUT_SECS: u64 = 15;
+const POLL_INTERVAL_MILLIS: u64 = 100;
+const RESTART_DELAY_SECS: u64 = 5;
+const MAX_RESTART_DELAY_SECS: u64 = 5 * 60;
+
+/// A running `ssh -N -L`, stopped when dropped
+pub struct Tunnel {
+    child: Child,
+    pub local_port: u16,
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Tunnel {
+    /// Waits for ssh to exit, returns what it printed. Gives up once `stop` is set, dropping the tunnel stops ssh
+    fn wait(mut self, stop: &AtomicBool) -> Option<String> {
+        while !stop.load(Ordering::SeqCst) {
+            match self.child.try_wait() {
+                Ok(None) => thread::sleep(Duration::from_millis(POLL_INTERVAL_MILLIS)),
+                _ => return Some(stderr_of(&mut self.child)),
+            }
+        }
+        None
+    }
+}
+
+/// Keeps a tunnel open, see `supervise`
+pub struct Supervisor {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Supervisor {
+    /// Stops the ssh process, it would outlive the server otherwise
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.handle.join();
+    }
+}
+
+fn stderr_of(child: &mut Child) -> String {
+    let mut stderr = String::new();
+    if let Some(ref mut pipe) = child.stderr {
+        let _ = pipe.read_to_string(&mut stderr);
+    }
+    stderr.trim().to_string()
+}
+
+/// Where the bastion should connect to, a unix socket for Postgres domains that use one
+pub fn forward_target(domain: &DomainInfo) -> String {
+    let (host, port) = match domain {
+        DomainInfo::Postgres { host, port, .. } if host.starts_with('/') =>
+            return format!("{}/.s.PGSQL.{}", host.trim_end_matches('/'), port),
+        DomainInfo::Postgres { host, port, .. } => (host, port),
+        DomainInfo::Redis { host, port, .. } => (host, port),
+    };
+
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// The domain as seen through the tunnel's local port
+pub fn local_domain(domain: &DomainInfo, local_port: u16) -> DomainInfo {
+    let mut local = domain.to_owned();
+    match local {
+        DomainInfo::Postgres { ref mut host, ref mut port, .. } | DomainInfo::Redis { ref mut host, ref mut port, .. } => {
+            *host = LOCAL_HOST.to_string();
+            *port = local_port;
+        },
+    }
+    local.set_ssh_tunnel(None);
+    local
+}
+
+/// The configured local port, or one that is free right now
+pub fn pick_local_port(tunnel: &SshTunnel) -> Result<u16, KakapoError> {
+    if let Some(local_port) = tunnel.local_port {
+        return Ok(local_port);
+    }
+
+    let listener = TcpListener::bind((LOCAL_HOST, 0))
+        .map_err(|err| KakapoError::io("Could not find a free local port", err))?;
+    listener.local_addr()
+        .map(|address| address.port())
+        .map_err(|err| KakapoError::io("Could not find a free local port", err))
+}
+
+fn ssh_args(tunnel: &SshTunnel, local_port: u16, target: &str) -> Vec<String> {
+    let mut args = vec![
+        "-N".to_string(),
+        "-o".to_string(), "ExitOnForwardFailure=yes".to_string(),
+        // never stop at a password or passphrase prompt, there is nobody to answer it
+        "-o".to_string(), "BatchMode=yes".to_string(),
+        "-o".to_string(), "ServerAliveInterval=15".to_string(),
+        "-o".to_string(), "ServerAliveCountMax=3".to_string(),
+        "-o".to_string(), "LogLevel=ERROR".to_string(),
+        "-p".to_string(), tunnel.port.to_string(),
+    ];
+
+    match tunnel.known_hosts {
+        KnownHostsPolicy::Strict => args.extend(vec!["-o".to_string(), "StrictHostKeyChecking=yes".to_string()]),
+        KnownHostsPolicy::AcceptNew => args.extend(vec!["-o".to_string(), "StrictHostKeyChecking=accept-new".to_string()]),
+        KnownHostsPolicy::Ignore => args.extend(vec![
+            "-o".to_string(), "StrictHostKeyChecking=no".to_string(),
+            "-o".to_string(), "UserKnownHostsFile=/dev/null".to_string(),
+        ]),
+    }
+
+    if let Some(ref key_path) = tunnel.key_path {
+        args.extend(vec![
+            "-i".to_string(), key_path.to_string_lossy().into_owned(),
+            "-o".to_string(), "IdentitiesOnly=yes".to_string(),
+        ]);
+    }
+
+    args.push("-L".to_string());
+    args.push(format!("{}:{}:{}", LOCAL_HOST, local_port, target));
+    args.push(format!("{}@{}", &tunnel.user, &tunnel.host));
+
+    args
+}
+
+/// Fails if something already listens on the port, it would answer in place of the tunnel
+fn check_port_is_free(tunnel: &SshTunnel, local_port: u16) -> Result<(), KakapoError> {
+    TcpListener::bind((LOCAL_HOST, local_port))
+        .map(|_| ())
+        .map_err(|err| KakapoError::ConnectionFailed {
+            target: tunnel.to_string(),
+            message: format!("the local port {} is not free: {}", local_port, err),
+        })
+}
+
+/// Starts ssh and waits until the local port accepts connections
+pub fn open(tunnel: &SshTunnel, target: &str, local_port: u16) -> Result<Tunnel, KakapoError> {
+    // once the port is checked to be free, only ssh's forward can accept connections on it
+    check_port_is_free(tunnel, local_port)?;
+
+    let args = ssh_args(tunnel, local_port, target);
+    let command = format!("ssh {}", args.join(" "));
+
+    let child = Command::new("ssh")
+        .args(&args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| KakapoError::external_command(&command, &err.to_string()))?;
+    let mut tunnel_process = Tunnel { child, local_port };
+
+    let address = SocketAddr::from(([127, 0, 0, 1], local_port));
+    let start = Instant::now();
+    loop {
+        if let Some(status) = tunnel_process.child.try_wait()? {
+            let stderr = stderr_of(&mut tunnel_process.child);
+            let message = if stderr.is_empty() { status.to_string() } else { stderr };
+            return Err(KakapoError::ConnectionFailed { target: tunnel.to_string(), message });
+        }
+
+        if TcpStream::connect_timeout(&address, Duration::from_millis(POLL_INTERVAL_MILLIS)).is_ok() {
+            return Ok(tunnel_process);
+        }
+
+        if start.elapsed() > Duration::from_secs(OPEN_TIMEOUT_SECS) {
+            return Err(KakapoError::ConnectionFailed {
+                target: tunnel.to_string(),
+                message: format!("the forward to {} did not open within {} seconds", target, OPEN_TIMEOUT_SECS),
+            });
+        }
+
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MILLIS));
+    }
+}
+
+/// Opens the tunnel for the domain on a fixed local port, and keeps reopening it whenever ssh exits
+pub fn supervise(name: &str, domain: &DomainInfo, tunnel: &SshTunnel) -> Result<(u16, Supervisor), KakapoError> {
+    let local_port = pick_local_port(tunnel)?;
+    let target = forward_target(domain);
+    let name = name.to_string();
+    let tunnel = tunnel.to_owned();
+
+    // the first attempt happens before the server starts, so the plugin finds the port open
+    let mut current = match open(&tunnel, &target, local_port) {
+        Ok(opened) => {
+            info!("Opened the ssh tunnel for {} via {} on {}:{}", &name, &tunnel, LOCAL_HOST, local_port);
+            Some(opened)
+        },
+        Err(err) => {
+            error!("Could not open the ssh tunnel for {}: {}", &name, &err);
+            None
+        },
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+    let handle = thread::spawn(move || {
+        let mut delay = RESTART_DELAY_SECS;
+        loop {
+            if let Some(running) = current.take() {
+                let opened_at = Instant::now();
+                let stderr = match running.wait(&thread_stop) {
+                    Some(stderr) => stderr,
+                    None => return,
+                };
+                warn!("The ssh tunnel for {} via {} closed: {}", &name, &tunnel, &stderr);
+
+                // a tunnel that stayed up for a while starts over with a short delay
+                if opened_at.elapsed() > Duration::from_secs(MAX_RESTART_DELAY_SECS) {
+                    delay = RESTART_DELAY_SECS;
+                }
+            }
+
+            let retry_at = Instant::now() + Duration::from_secs(delay);
+            while Instant::now() < retry_at {
+                if thread_stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(POLL_INTERVAL_MILLIS));
+            }
+            delay = (delay * 2).min(MAX_RESTART_DELAY_SECS);
+
+            match open(&tunnel, &target, local_port) {
+                Ok(opened) => {
+                    info!("Reopened the ssh tunnel for {} via {}", &name, &tunnel);
+                    current = Some(opened);
+                },
+                Err(err) => error!("Could not reopen the ssh tunnel for {}: {}", &name, &err),
+            }
+        }
+    });
+
+    Ok((local_port, Supervisor { stop, handle }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use wizard::{DomainInfo, KnownHostsPolicy, PostgresOptions, SshTunnel};
+
+    use super::{forward_target, local_domain, open, ssh_args, supervise, LOCAL_HOST, RESTART_DELAY_SECS};
+
+    fn bastion(known_hosts: KnownHostsPolicy, key_path: Option<PathBuf>) -> SshTunnel {
+        SshTunnel {
+            host: "bastion.example.com".to_string(),
+            port: 2222,
+            user: "deploy".to_string(),
+            key_path,
+            known_hosts,
+            local_port: None,
+        }
+    }
+
+    fn postgres(host: &str) -> DomainInfo {
+        DomainInfo::Postgres {
+            host: host.to_string(),
+            port: 5432,
+            user: "kakapo".to_string(),
+            pass: "secret".to_string(),
+            database: "kakapo".to_string(),
+            options: PostgresOptions::default(),
+            ssh_tunnel: Some(bastion(KnownHostsPolicy::Strict, None)),
+        }
+    }
+
+    fn has_option(args: &[String], option: &str) -> bool {
+        args.windows(2).any(|pair| pair[0] == "-o" && pair[1] == option)
+    }
+
+    #[test]
+    fn builds_the_ssh_arguments() {
+        let args = ssh_args(&bastion(KnownHostsPolicy::Strict, None), 15432, "db.internal:5432");
+
+        assert_eq!(args[0], "-N");
+        assert!(has_option(&args, "ExitOnForwardFailure=yes"));
+        assert!(has_option(&args, "BatchMode=yes"));
+        assert!(has_option(&args, "StrictHostKeyChecking=yes"));
+        assert!(args.windows(2).any(|pair| pair[0] == "-p" && pair[1] == "2222"));
+        assert!(args.windows(2).any(|pair| pair[0] == "-L" && pair[1] == "127.0.0.1:15432:db.internal:5432"));
+        assert!(!args.contains(&"-i".to_string()));
+        assert_eq!(args.last().unwrap(), "deploy@bastion.example.com");
+    }
+
+    #[test]
+    fn follows_the_known_hosts_policy_and_key() {
+        let key_path = PathBuf::from("/home/kakapo/.ssh/id_ed25519");
+        let args = ssh_args(&bastion(KnownHostsPolicy::Ignore, Some(key_path)), 15432, "db.internal:5432");
+
+        assert!(has_option(&args, "StrictHostKeyChecking=no"));
+        assert!(has_option(&args, "UserKnownHostsFile=/dev/null"));
+        assert!(has_option(&args, "IdentitiesOnly=yes"));
+        assert!(args.windows(2).any(|pair| pair[0] == "-i" && pair[1] == "/home/kakapo/.ssh/id_ed25519"));
+
+        let args = ssh_args(&bastion(KnownHostsPolicy::AcceptNew, None), 15432, "db.internal:5432");
+        assert!(has_option(&args, "StrictHostKeyChecking=accept-new"));
+    }
+
+    #[test]
+    fn forwards_to_the_domain_as_seen_from_the_bastion() {
+        assert_eq!(forward_target(&postgres("db.internal")), "db.internal:5432");
+        assert_eq!(forward_target(&postgres("::1")), "[::1]:5432");
+        assert_eq!(forward_target(&postgres("/var/run/postgresql/")), "/var/run/postgresql/.s.PGSQL.5432");
+    }
+
+    #[test]
+    fn points_the_domain_at_the_local_port() {
+        match local_domain(&postgres("db.internal"), 15432) {
+            DomainInfo::Postgres { host, port, ssh_tunnel, database, .. } => {
+                assert_eq!(host, LOCAL_HOST);
+                assert_eq!(port, 15432);
+                assert_eq!(database, "kakapo");
+                assert!(ssh_tunnel.is_none());
+            },
+            domain => panic!("unexpected domain {:?}", domain),
+        }
+    }
+
+    #[test]
+    fn refuses_a_local_port_that_is_taken() {
+        let listener = TcpListener::bind((LOCAL_HOST, 0)).unwrap();
+        let local_port = listener.local_addr().unwrap().port();
+
+        assert!(open(&bastion(KnownHostsPolicy::Strict, None), "db.internal:5432", local_port).is_err());
+    }
+
+    #[test]
+    fn stops_while_waiting_to_reopen() {
+        // nothing listens on the bastion's port, ssh fails right away and the supervisor waits to retry
+        let mut tunnel = bastion(KnownHostsPolicy::Ignore, None);
+        tunnel.host = LOCAL_HOST.to_string();
+        tunnel.port = TcpListener::bind((LOCAL_HOST, 0)).unwrap().local_addr().unwrap().port();
+        let (_, supervisor) = supervise("test", &postgres("db.internal"), &tunnel).unwrap();
+
+        let start = Instant::now();
+        supervisor.stop();
+        assert!(start.elapsed() < Duration::from_secs(RESTART_DELAY_SECS));
+    }
+
+    /// Needs an sshd on localhost that accepts `KAKAPO_TEST_SSH_USER` with the key in `KAKAPO_TEST_SSH_KEY`,
+    /// run it with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn forwards_through_a_local_sshd() {
+        let user = env::var("KAKAPO_TEST_SSH_USER").expect("KAKAPO_TEST_SSH_USER is not set");
+        let key_path = env::var("KAKAPO_TEST_SSH_KEY").expect("KAKAPO_TEST_SSH_KEY is not set");
+        let port = env::var("KAKAPO_TEST_SSH_PORT").ok().and_then(|port| port.parse().ok()).unwrap_or(22);
+
+        // an echo server stands in for the database
+        let echo = TcpListener::bind((LOCAL_HOST, 0)).unwrap();
+        let target = format!("{}:{}", LOCAL_HOST, echo.local_addr().unwrap().port());
+        thread::spawn(move || {
+            if let Ok((mut stream, _)) = echo.accept() {
+                let mut buffer = [0; 4];
+                if stream.read_exact(&mut buffer).is_ok() {
+                    let _ = stream.write_all(&buffer);
+                }
+            }
+        });
+
+        let tunnel = SshTunnel {
+            host: LOCAL_HOST.to_string(),
+            port,
+            user,
+            key_path: Some(PathBuf::from(key_path)),
+            known_hosts: KnownHostsPolicy::Ignore,
+            local_port: None,
+        };
+        let local_port = super::pick_local_port(&tunnel).unwrap();
+        let opened = open(&tunnel, &target, local_port).unwrap();
+
+        let mut stream = TcpStream::connect((LOCAL_HOST, opened.local_port)).unwrap();
+        stream.write_all(b"ping").unwrap();
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"ping");
+    }
+}
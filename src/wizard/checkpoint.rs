@@ -0,0 +1,78 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use wizard::ConfigData;
+
+/// Progress of an unfinished wizard, kept next to the config file until the config is written
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Checkpoint {
+    /// Where the config will be written, this can differ from the config path the wizard started with
+    pub config_path: PathBuf,
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub instance: Option<String>,
+    /// The profile being configured, `config` is the whole config with the profile's changes merged in
+    #[serde(default)]
+    pub profile: Option<String>,
+    pub completed_steps: Vec<String>,
+    pub config: ConfigData,
+}
+
+impl Checkpoint {
+    pub fn new(config: &ConfigData, completed_steps: &[String], profile: Option<String>) -> Self {
+        Checkpoint {
+            config_path: config.config_path.to_owned(),
+            owner: config.owner.to_owned(),
+            instance: config.instance.to_owned(),
+            profile,
+            completed_steps: completed_steps.to_vec(),
+            config: config.to_owned(),
+        }
+    }
+
+    pub fn into_config(self) -> ConfigData {
+        let mut config = self.config.with_path(self.config_path);
+        config.owner = self.owner;
+        config.instance = self.instance;
+        config
+    }
+}
+
+fn checkpoint_path(config_path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.checkpoint", config_path.display()))
+}
+
+pub fn load(config_path: &Path) -> Option<Checkpoint> {
+    let data = fs::read_to_string(checkpoint_path(config_path)).ok()?;
+    serde_yaml::from_str(&data).ok()
+}
+
+/// The checkpoint contains passwords, so it is only readable by the operator
+pub fn save(config_path: &Path, checkpoint: &Checkpoint) -> Result<(), String> {
+    let data = serde_yaml::to_string(checkpoint)
+        .map_err(|err| err.to_string())?;
+
+    if let Some(parent) = config_path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|err| err.to_string())?;
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(checkpoint_path(config_path))
+        .map_err(|err| err.to_string())?;
+
+    file.write_all(data.as_bytes())
+        .map_err(|err| err.to_string())
+}
+
+pub fn remove(config_path: &Path) {
+    let _ = fs::remove_file(checkpoint_path(config_path));
+}
@@ -0,0 +1,532 @@
+//! Finds domains that are already described somewhere else: libpq's `~/.pg_service.conf` with the passwords from
+//! `~/.pgpass`, and the Postgres and Redis services of a `docker-compose.yml`
+
+use std::collections::BTreeMap;
+use std::env;
+use std::env::home_dir;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde_yaml::Value;
+
+use error::KakapoError;
+use wizard::utils;
+use wizard::DomainInfo;
+use wizard::PostgresOptions;
+
+pub const PG_SERVICE_FILE: &'static str = ".pg_service.conf";
+pub const PGPASS_FILE: &'static str = ".pgpass";
+pub const COMPOSE_FILES: [&'static str; 4] = ["docker-compose.yml", "docker-compose.yaml", "compose.yml", "compose.yaml"];
+
+const POSTGRES_PORT: u16 = 5432;
+const REDIS_PORT: u16 = 6379;
+const DEFAULT_HOST: &'static str = "localhost";
+
+/// A domain found in another tool's configuration
+#[derive(Serialize, Clone, Debug)]
+pub struct Candidate {
+    /// Where it was found, e.g. `~/.pg_service.conf [billing]`
+    pub source: String,
+    pub name: String,
+    pub domain: DomainInfo,
+    /// No password was found for a Postgres domain
+    pub missing_password: bool,
+}
+
+fn home_file(variable: &str, file_name: &str) -> Option<PathBuf> {
+    match env::var(variable) {
+        Ok(ref path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => home_dir().map(|home| home.join(file_name)),
+    }
+}
+
+pub fn pg_service_path() -> Option<PathBuf> {
+    home_file("PGSERVICEFILE", PG_SERVICE_FILE)
+}
+
+pub fn pgpass_path() -> Option<PathBuf> {
+    home_file("PGPASSFILE", PGPASS_FILE)
+}
+
+/// The compose file in `dir`, under any of its usual names
+pub fn find_compose_file(dir: &Path) -> Option<PathBuf> {
+    COMPOSE_FILES
+        .iter()
+        .map(|file_name| dir.join(file_name))
+        .find(|path| path.exists())
+}
+
+fn read(path: &Path) -> Result<String, KakapoError> {
+    fs::read_to_string(path)
+        .map_err(|err| KakapoError::io(&format!("Could not read {}", path.display()), err))
+}
+
+/// One line of `.pgpass`, `*` matches anything
+#[derive(Clone, Debug)]
+pub struct PgpassEntry {
+    host: String,
+    port: String,
+    database: String,
+    user: String,
+    pass: String,
+}
+
+/// Splits on the colons that aren't escaped with a backslash
+fn split_pgpass_line(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => fields.last_mut().unwrap().extend(chars.next()),
+            ':' => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+
+    fields
+}
+
+pub fn parse_pgpass(text: &str) -> Vec<PgpassEntry> {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(split_pgpass_line)
+        .filter(|fields| fields.len() == 5)
+        .map(|mut fields| PgpassEntry {
+            pass: fields.pop().unwrap_or_default(),
+            user: fields.pop().unwrap_or_default(),
+            database: fields.pop().unwrap_or_default(),
+            port: fields.pop().unwrap_or_default(),
+            host: fields.pop().unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// The password of the first matching entry, like libpq does it
+pub fn find_password(entries: &[PgpassEntry], host: &str, port: u16, database: &str, user: &str) -> Option<String> {
+    let matches = |pattern: &str, value: &str| pattern == "*" || pattern == value;
+    let port = port.to_string();
+
+    entries
+        .iter()
+        .find(|entry| matches(&entry.host, host) && matches(&entry.port, &port) &&
+            matches(&entry.database, database) && matches(&entry.user, user))
+        .map(|entry| entry.pass.to_owned())
+}
+
+/// The `[service]` sections of a `pg_service.conf`, with their `key=value` settings
+pub fn parse_pg_service(text: &str) -> BTreeMap<String, BTreeMap<String, String>> {
+    let mut services = BTreeMap::new();
+    let mut current: Option<String> = None;
+
+    for line in text.lines().map(|line| line.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            let name = line[1..line.len() - 1].trim().to_string();
+            services.entry(name.to_owned()).or_insert_with(BTreeMap::new);
+            current = Some(name);
+            continue;
+        }
+
+        if let (Some(ref service), Some(separator)) = (&current, line.find('=')) {
+            let key = line[..separator].trim().to_string();
+            let value = line[separator + 1..].trim().to_string();
+            services.entry(service.to_owned()).or_insert_with(BTreeMap::new).insert(key, value);
+        }
+    }
+
+    services
+}
+
+fn default_user() -> String {
+    env::var("PGUSER")
+        .or_else(|_| env::var("USER"))
+        .unwrap_or_else(|_| "postgres".to_string())
+}
+
+/// Turns the settings of a Postgres service into a candidate, looking up the password in `.pgpass` if needed
+fn postgres_candidate(source: String, name: &str, settings: &BTreeMap<String, String>, pgpass: &[PgpassEntry]) -> Candidate {
+    let setting = |key: &str| settings.get(key).filter(|value| !value.is_empty()).cloned();
+
+    let host = setting("host").or_else(|| setting("hostaddr")).unwrap_or_else(|| DEFAULT_HOST.to_string());
+    let port = setting("port").and_then(|port| port.parse().ok()).unwrap_or(POSTGRES_PORT);
+    let user = setting("user").unwrap_or_else(default_user);
+    let database = setting("dbname").unwrap_or_else(|| user.to_owned());
+    let pass = setting("password").or_else(|| find_password(pgpass, &host, port, &database, &user));
+
+    let options = PostgresOptions {
+        sslmode: setting("sslmode").and_then(|sslmode| sslmode.parse().ok()),
+        ssl_root_cert: setting("sslrootcert").map(PathBuf::from),
+        connect_timeout_secs: setting("connect_timeout").and_then(|timeout| timeout.parse().ok()),
+        application_name: setting("application_name"),
+        ..PostgresOptions::default()
+    };
+
+    Candidate {
+        source,
+        name: name.to_string(),
+        missing_password: pass.is_none(),
+        domain: DomainInfo::Postgres {
+            host, port, user,
+            pass: pass.unwrap_or_default(),
+            database,
+            options,
+            ssh_tunnel: None,
+        },
+    }
+}
+
+pub fn from_pg_service(path: &Path, pgpass: &[PgpassEntry]) -> Result<Vec<Candidate>, KakapoError> {
+    let services = parse_pg_service(&read(path)?);
+
+    Ok(services
+        .iter()
+        .map(|(name, settings)| {
+            let source = format!("{} [{}]", path.display(), name);
+            postgres_candidate(source, name, settings, pgpass)
+        })
+        .collect())
+}
+
+fn scalar_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.to_owned()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Resolves `${VAR}`, `${VAR:-default}`, `${VAR-default}` and `$VAR` from the environment, the way compose does
+fn interpolate(text: &str) -> String {
+    interpolate_with(text, |variable| env::var(variable).ok())
+}
+
+/// `${VAR:-default}` replaces an unset or empty variable, `${VAR-default}` only an unset one
+fn interpolate_with<F: Fn(&str) -> Option<String>>(text: &str, lookup: F) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        if rest.starts_with('$') {
+            result.push('$');
+            rest = &rest[1..];
+        } else if rest.starts_with('{') {
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => {
+                    result.push('$');
+                    continue;
+                },
+            };
+            let expression = &rest[1..end];
+            let value = match expression.find('-') {
+                Some(separator) if expression[..separator].ends_with(':') => lookup(&expression[..separator - 1])
+                    .filter(|value| !value.is_empty())
+                    .unwrap_or_else(|| expression[separator + 1..].to_string()),
+                Some(separator) => lookup(&expression[..separator])
+                    .unwrap_or_else(|| expression[separator + 1..].to_string()),
+                None => lookup(expression).unwrap_or_default(),
+            };
+            result.push_str(&value);
+            rest = &rest[end + 1..];
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or_else(|| rest.len());
+            if end == 0 {
+                result.push('$');
+            }
+            result.push_str(&lookup(&rest[..end]).unwrap_or_default());
+            rest = &rest[end..];
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// `environment` as either a list of `KEY=value` or a mapping
+fn compose_environment(service: &Value) -> BTreeMap<String, String> {
+    let mut environment = BTreeMap::new();
+
+    match service.get("environment") {
+        Some(Value::Sequence(entries)) => {
+            for entry in entries.iter().filter_map(scalar_string) {
+                let mut parts = entry.splitn(2, '=');
+                if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+                    environment.insert(key.to_string(), interpolate(value));
+                }
+            }
+        },
+        Some(Value::Mapping(entries)) => {
+            for (key, value) in entries {
+                if let (Some(key), Some(value)) = (scalar_string(key), scalar_string(value)) {
+                    environment.insert(key, interpolate(&value));
+                }
+            }
+        },
+        _ => {},
+    }
+
+    environment
+}
+
+/// The address to connect to for a port published on `host_ip`, the local host for one published on all addresses
+fn published_host(host_ip: Option<&str>) -> String {
+    let host = host_ip
+        .unwrap_or("")
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+
+    match host {
+        "" | "0.0.0.0" | "::" => DEFAULT_HOST.to_string(),
+        host => host.to_string(),
+    }
+}
+
+/// The host address and port that the container's `target` port is published on
+fn published_port(service: &Value, target: u16) -> Option<(String, u16)> {
+    let ports = match service.get("ports") {
+        Some(Value::Sequence(ports)) => ports,
+        _ => return None,
+    };
+
+    ports.iter().filter_map(|port| match port {
+        Value::Mapping(_) => {
+            let port_target = port.get("target").and_then(scalar_string)?;
+            let published = port.get("published").and_then(scalar_string)?;
+            let host_ip = port.get("host_ip").and_then(scalar_string);
+            if port_target.parse::<u16>().ok()? != target {
+                return None;
+            }
+            Some((published_host(host_ip.as_ref().map(|host| host.as_str())), interpolate(&published).parse().ok()?))
+        },
+        port => {
+            // `[host_ip:]published:target[/protocol]`, a lone port is published on a random host port
+            let port = interpolate(&scalar_string(port)?);
+            let port = port.split('/').next()?;
+            let parts: Vec<_> = port.rsplitn(3, ':').collect();
+            if parts.len() < 2 || parts[0].parse::<u16>().ok()? != target {
+                return None;
+            }
+            Some((published_host(parts.get(2).cloned()), parts[1].parse().ok()?))
+        },
+    }).next()
+}
+
+/// `--requirepass` from the redis-server command line
+fn redis_password(service: &Value, environment: &BTreeMap<String, String>) -> Option<String> {
+    let command = match service.get("command") {
+        Some(Value::Sequence(args)) => args.iter().filter_map(scalar_string).collect::<Vec<_>>(),
+        Some(command) => scalar_string(command)?.split_whitespace().map(|arg| arg.to_string()).collect(),
+        None => vec![],
+    };
+
+    command
+        .iter()
+        .position(|arg| arg == "--requirepass")
+        .and_then(|idx| command.get(idx + 1))
+        .map(|pass| interpolate(pass.trim_matches(|c: char| c == '"' || c == '\'')))
+        .or_else(|| environment.get("REDIS_PASSWORD").cloned())
+        .filter(|pass| !pass.is_empty())
+}
+
+fn image_is(service: &Value, names: &[&str]) -> bool {
+    let image = match service.get("image").and_then(scalar_string) {
+        Some(image) => image,
+        None => return false,
+    };
+
+    // e.g. `docker.io/library/postgres:11-alpine` or `postgis/postgis`
+    let repository = image.split(':').next().unwrap_or("");
+    let name = repository.rsplit('/').next().unwrap_or("");
+    names.iter().any(|candidate| name == *candidate)
+}
+
+pub fn from_compose(path: &Path) -> Result<Vec<Candidate>, KakapoError> {
+    let compose: Value = serde_yaml::from_str(&read(path)?)
+        .map_err(|err| KakapoError::ConfigParse { path: path.to_owned(), message: err.to_string() })?;
+
+    let services = match compose.get("services") {
+        Some(Value::Mapping(services)) => services,
+        _ => return Ok(vec![]),
+    };
+
+    let mut candidates = vec![];
+    for (name, service) in services {
+        let name = match scalar_string(name) {
+            Some(name) => name,
+            None => continue,
+        };
+        let source = format!("{} [{}]", path.display(), name);
+        let environment = compose_environment(service);
+
+        if image_is(service, &["postgres", "postgis", "timescaledb"]) {
+            let (host, port) = match published_port(service, POSTGRES_PORT) {
+                Some(published) => published,
+                None => continue,
+            };
+            let user = environment.get("POSTGRES_USER").cloned().unwrap_or_else(|| "postgres".to_string());
+            let database = environment.get("POSTGRES_DB").cloned().unwrap_or_else(|| user.to_owned());
+            let pass = environment.get("POSTGRES_PASSWORD").cloned();
+
+            candidates.push(Candidate {
+                source,
+                name,
+                missing_password: pass.is_none(),
+                domain: DomainInfo::Postgres {
+                    host, port, user,
+                    pass: pass.unwrap_or_default(),
+                    database,
+                    options: PostgresOptions::default(),
+                    ssh_tunnel: None,
+                },
+            });
+        } else if image_is(service, &["redis"]) {
+            let (host, port) = match published_port(service, REDIS_PORT) {
+                Some(published) => published,
+                None => continue,
+            };
+
+            candidates.push(Candidate {
+                source,
+                name,
+                missing_password: false,
+                domain: DomainInfo::Redis {
+                    host, port,
+                    pass: redis_password(service, &environment),
+                    database: 0,
+                    ssh_tunnel: None,
+                },
+            });
+        }
+    }
+
+    Ok(candidates)
+}
+
+/// Keeps the proposed names unique among themselves and the existing domains, a parrot name replaces a taken one
+pub fn resolve_names<F: Fn(&str) -> bool>(candidates: &mut [Candidate], is_taken: F) {
+    let mut proposed: Vec<String> = vec![];
+
+    for candidate in candidates.iter_mut() {
+        let name_taken = |name: &str| is_taken(name) || proposed.iter().any(|other| other == name);
+        if candidate.name.is_empty() || name_taken(&candidate.name) {
+            candidate.name = utils::unique_parrot_name(name_taken);
+        }
+        proposed.push(candidate.name.to_owned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use super::{find_password, interpolate_with, parse_pg_service, parse_pgpass, published_port};
+
+    fn lookup(variable: &str) -> Option<String> {
+        match variable {
+            "SET" => Some("value".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn interpolates_like_compose() {
+        let cases = [
+            ("${SET}", "value"),
+            ("$SET", "value"),
+            ("${UNSET}", ""),
+            ("${SET:-default}", "value"),
+            ("${EMPTY:-default}", "default"),
+            ("${UNSET:-default}", "default"),
+            ("${SET-default}", "value"),
+            ("${EMPTY-default}", ""),
+            ("${UNSET-default}", "default"),
+            ("${UNSET:-a-b}", "a-b"),
+            ("$$SET", "$SET"),
+            ("postgres://${SET}@db:5432", "postgres://value@db:5432"),
+            ("${UNCLOSED", "${UNCLOSED"),
+        ];
+
+        for &(text, expected) in cases.iter() {
+            assert_eq!(interpolate_with(text, lookup), expected, "interpolating {}", text);
+        }
+    }
+
+    fn service(yaml: &str) -> Value {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn finds_the_published_port() {
+        let cases = [
+            ("ports: ['5432:5432']", Some(("localhost", 5432))),
+            ("ports: ['15432:5432/tcp']", Some(("localhost", 15432))),
+            ("ports: ['127.0.0.1:15432:5432']", Some(("127.0.0.1", 15432))),
+            ("ports: ['0.0.0.0:15432:5432']", Some(("localhost", 15432))),
+            ("ports: ['[::1]:5432:5432']", Some(("::1", 5432))),
+            ("ports: ['5432']", None),
+            ("ports: ['6379:6379']", None),
+            ("ports: [{target: 5432, published: 15432}]", Some(("localhost", 15432))),
+            ("ports: [{target: 5432, published: '15432', host_ip: 127.0.0.1}]", Some(("127.0.0.1", 15432))),
+            ("ports: [{target: 5432, published: 15432, host_ip: 0.0.0.0}]", Some(("localhost", 15432))),
+            ("ports: [{target: 6379, published: 6379}]", None),
+            ("image: postgres", None),
+        ];
+
+        for &(yaml, expected) in cases.iter() {
+            let expected = expected.map(|(host, port)| (host.to_string(), port));
+            assert_eq!(published_port(&service(yaml), 5432), expected, "ports of {}", yaml);
+        }
+    }
+
+    #[test]
+    fn parses_pgpass() {
+        let entries = parse_pgpass("\
+# host:port:database:user:password
+db.internal:5432:billing:kakapo:s3cret
+db\\:internal:*:*:kakapo:with\\:colon\\\\
+too:few:fields
+*:*:*:*:fallback
+");
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(find_password(&entries, "db.internal", 5432, "billing", "kakapo"), Some("s3cret".to_string()));
+        assert_eq!(find_password(&entries, "db:internal", 5433, "shop", "kakapo"), Some("with:colon\\".to_string()));
+        assert_eq!(find_password(&entries, "elsewhere", 5432, "shop", "someone"), Some("fallback".to_string()));
+        assert_eq!(find_password(&entries[..2], "elsewhere", 5432, "shop", "someone"), None);
+    }
+
+    #[test]
+    fn parses_pg_service() {
+        let services = parse_pg_service("\
+# services
+orphan=ignored
+[billing]
+host = db.internal
+port=5433
+dbname=billing
+
+[ empty ]
+sslmode=require=really
+");
+
+        assert_eq!(services.len(), 2);
+        assert_eq!(services["billing"]["host"], "db.internal");
+        assert_eq!(services["billing"]["port"], "5433");
+        assert_eq!(services["billing"]["dbname"], "billing");
+        assert_eq!(services["empty"].len(), 1);
+        assert_eq!(services["empty"]["sslmode"], "require=really");
+    }
+}
@@ -1,30 +1,53 @@
 
 use std::path::PathBuf;
+use std::path::Path;
 use std::error::Error;
 use std::fs;
+use std::io;
 use std::collections::BTreeMap;
+use std::collections::VecDeque;
 use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 
 use ansi_term::Style;
 use ansi_term::Color::{Green, Yellow, Red, RGB};
 use inflector::Inflector;
+use schemars;
+use schemars::JsonSchema;
+
+use config::document;
+use config::format::ConfigFormat;
+use config::layers;
+use config::ConfigSource;
+use error::KakapoError;
+use logging::LoggingConfig;
+use output;
+use output::OutputFormat;
 
 mod steps;
 mod data;
-mod utils;
+pub mod utils;
+mod checkpoint;
+mod review;
+pub mod prompt;
+pub mod connection;
+pub mod import;
 
-use self::steps::get_theme;
-use dialoguer::Confirmation;
+pub use self::steps::get_theme;
+pub use self::steps::has_daemon;
+use self::checkpoint::Checkpoint;
+use self::prompt::{Confirmation, Select};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Reason {
     NoConfigFile,
     InitialConfigure,
     ReconfigureAll(PathBuf),
-    Reconfigure(String, PathBuf),
+    Reconfigure(Vec<String>, PathBuf),
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum Version {
     V1,
@@ -33,7 +56,119 @@ impl Default for Version {
     fn default() -> Self { Version::V1 }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_POOL_SIZE: u32 = 4;
+pub const DEFAULT_APPLICATION_NAME: &'static str = "kakapo";
+
+/// Same as libpq's `sslmode`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SslMode {
+    Disable,
+    Allow,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+impl Default for SslMode {
+    fn default() -> Self { SslMode::Prefer }
+}
+
+impl SslMode {
+    pub const ALL: [SslMode; 6] = [SslMode::Disable, SslMode::Allow, SslMode::Prefer, SslMode::Require, SslMode::VerifyCa, SslMode::VerifyFull];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SslMode::Disable => "disable",
+            SslMode::Allow => "allow",
+            SslMode::Prefer => "prefer",
+            SslMode::Require => "require",
+            SslMode::VerifyCa => "verify-ca",
+            SslMode::VerifyFull => "verify-full",
+        }
+    }
+
+    /// Whether the connection fails rather than going on without TLS
+    pub fn requires_tls(&self) -> bool {
+        *self == SslMode::Require || self.verifies_certificate()
+    }
+
+    /// Whether the server's certificate is checked against the CA
+    pub fn verifies_certificate(&self) -> bool {
+        *self == SslMode::VerifyCa || *self == SslMode::VerifyFull
+    }
+}
+
+impl fmt::Display for SslMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for SslMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        SslMode::ALL
+            .iter()
+            .find(|candidate| candidate.as_str() == mode)
+            .cloned()
+            .ok_or_else(|| format!("unknown sslmode '{}'", mode))
+    }
+}
+
+/// Connection settings that most setups leave alone, anything missing gets the default.
+/// A `host` starting with `/` is the directory of the server's unix socket
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+pub struct PostgresOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sslmode: Option<SslMode>,
+    /// CA bundle to verify the server's certificate with, the system's CAs by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssl_root_cert: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout_secs: Option<u64>,
+    /// No timeout by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub statement_timeout_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_size: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub application_name: Option<String>,
+}
+
+impl PostgresOptions {
+    pub fn is_default(&self) -> bool {
+        *self == PostgresOptions::default()
+    }
+
+    pub fn sslmode(&self) -> SslMode {
+        self.sslmode.unwrap_or_default()
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs.unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS))
+    }
+
+    pub fn statement_timeout(&self) -> Option<Duration> {
+        self.statement_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn pool_size(&self) -> u32 {
+        self.pool_size.unwrap_or(DEFAULT_POOL_SIZE)
+    }
+
+    pub fn application_name(&self) -> &str {
+        self.application_name
+            .as_ref()
+            .map(|name| name.as_str())
+            .unwrap_or(DEFAULT_APPLICATION_NAME)
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
 pub struct Manager {
     #[serde(rename = "type")]
     pub db_type: String,
@@ -42,9 +177,73 @@ pub struct Manager {
     pub user: String,
     pub pass: String,
     pub database: String,
+    #[serde(default, skip_serializing_if = "PostgresOptions::is_default")]
+    pub options: PostgresOptions,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct AdminInfo {
+    pub user: String,
+    /// Never the password itself, see `utils::hash_password`
+    pub pass_hash: String,
+    pub email: String,
+    pub display_name: String,
+}
+
+/// What ssh does with a bastion whose host key isn't in `known_hosts` yet
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum KnownHostsPolicy {
+    /// Refuse to connect
+    Strict,
+    /// Add the key on the first connection, refuse if it changes later
+    AcceptNew,
+    /// Don't check the host key at all
+    Ignore,
+}
+
+impl Default for KnownHostsPolicy {
+    fn default() -> Self { KnownHostsPolicy::Strict }
+}
+
+impl KnownHostsPolicy {
+    pub const ALL: [KnownHostsPolicy; 3] = [KnownHostsPolicy::Strict, KnownHostsPolicy::AcceptNew, KnownHostsPolicy::Ignore];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KnownHostsPolicy::Strict => "strict",
+            KnownHostsPolicy::AcceptNew => "accept-new",
+            KnownHostsPolicy::Ignore => "ignore",
+        }
+    }
+}
+
+fn default_ssh_port() -> u16 { 22 }
+
+/// The domain is reached through a local port forwarded over ssh by a bastion host
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct SshTunnel {
+    pub host: String,
+    #[serde(default = "default_ssh_port")]
+    pub port: u16,
+    pub user: String,
+    /// Private key to log in with, the ssh agent and ssh's default keys are used without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_path: Option<PathBuf>,
+    #[serde(default)]
+    pub known_hosts: KnownHostsPolicy,
+    /// Port on 127.0.0.1 to forward, a free one is picked without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_port: Option<u16>,
+}
+
+impl fmt::Display for SshTunnel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ssh://{}@{}:{}", self.user, self.host, self.port)
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
 #[serde(tag = "type")]
 #[serde(rename_all = "camelCase")]
 pub enum DomainInfo {
@@ -54,50 +253,377 @@ pub enum DomainInfo {
         user: String,
         pass: String,
         database: String,
+        #[serde(default, skip_serializing_if = "PostgresOptions::is_default")]
+        options: PostgresOptions,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ssh_tunnel: Option<SshTunnel>,
+    },
+    Redis {
+        host: String,
+        port: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pass: Option<String>,
+        #[serde(default)]
+        database: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ssh_tunnel: Option<SshTunnel>,
     },
 }
 
+impl DomainInfo {
+    pub fn ssh_tunnel(&self) -> Option<&SshTunnel> {
+        match self {
+            DomainInfo::Postgres { ssh_tunnel, .. } => ssh_tunnel.as_ref(),
+            DomainInfo::Redis { ssh_tunnel, .. } => ssh_tunnel.as_ref(),
+        }
+    }
+
+    pub fn set_ssh_tunnel(&mut self, tunnel: Option<SshTunnel>) {
+        match self {
+            DomainInfo::Postgres { ssh_tunnel, .. } => *ssh_tunnel = tunnel,
+            DomainInfo::Redis { ssh_tunnel, .. } => *ssh_tunnel = tunnel,
+        }
+    }
+}
+
 impl fmt::Display for DomainInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             DomainInfo::Postgres { user, host, port, database, ..} => {
-                write!(f, "Postgres [postgres://{}:*****@{}:{}/{}]", user, host, port, database)
+                write!(f, "Postgres [postgres://{}:*****@{}:{}/{}]", user, host, port, database)?;
             },
+            DomainInfo::Redis { host, port, pass, database, .. } => {
+                let auth = if pass.is_some() { ":*****@" } else { "" };
+                write!(f, "Redis [redis://{}{}:{}/{}]", auth, host, port, database)?;
+            },
+        }
+
+        match self.ssh_tunnel() {
+            Some(tunnel) => write!(f, " via {}", tunnel),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn print_domains(domains: &BTreeMap<String, DomainInfo>) {
+    for (domain_name, domain_data) in domains {
+        println!("{}=> {}", RGB(131, 221, 2).bold().paint(format!("{: <16}", &domain_name)), &domain_data);
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "camelCase")]
+pub enum TlsInfo {
+    Manual {
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    },
+    SelfSigned {
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    },
+    LetsEncrypt {
+        email: String,
+        directory_url: String,
+        http_port: u16,
+        /// CA that signed the ACME directory's own certificate, for test servers like Pebble
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        directory_ca: Option<PathBuf>,
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    },
+}
+
+impl TlsInfo {
+    /// The certificate and the key the server should use
+    pub fn files(&self) -> (&Path, &Path) {
+        match self {
+            TlsInfo::Manual { cert_path, key_path } => (cert_path.as_path(), key_path.as_path()),
+            TlsInfo::SelfSigned { cert_path, key_path } => (cert_path.as_path(), key_path.as_path()),
+            TlsInfo::LetsEncrypt { cert_path, key_path, .. } => (cert_path.as_path(), key_path.as_path()),
+        }
+    }
+
+    /// The same TLS setup with the certificate and the key somewhere else
+    pub fn with_files(&self, cert_path: PathBuf, key_path: PathBuf) -> TlsInfo {
+        match self {
+            TlsInfo::Manual { .. } => TlsInfo::Manual { cert_path, key_path },
+            TlsInfo::SelfSigned { .. } => TlsInfo::SelfSigned { cert_path, key_path },
+            TlsInfo::LetsEncrypt { email, directory_url, http_port, directory_ca, .. } => TlsInfo::LetsEncrypt {
+                email: email.to_owned(),
+                directory_url: directory_url.to_owned(),
+                http_port: *http_port,
+                directory_ca: directory_ca.to_owned(),
+                cert_path,
+                key_path,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct ServerInfo {
+    /// The public name, also the name on the certificate
+    pub host: String,
+    pub port: u16,
+    /// The address to listen on, see `ServerInfo::bind_address`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind: Option<String>,
+    pub tls: Option<TlsInfo>,
+}
+
+impl ServerInfo {
+    /// The configured address, or 127.0.0.1 behind a proxy without TLS and 0.0.0.0 when serving TLS itself
+    pub fn bind_address(&self) -> &str {
+        match self.bind {
+            Some(ref bind) => bind.as_str(),
+            None if self.tls.is_some() => "0.0.0.0",
+            None => "127.0.0.1",
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+/// Where to reach a server listening on `bind` from this machine, the loopback address for the wildcard addresses
+pub fn local_address(bind: &str) -> &str {
+    match bind {
+        "0.0.0.0" | "" => "127.0.0.1",
+        "::" | "[::]" => "::1",
+        bind => bind,
+    }
+}
+
+/// Sections that replace the base config's when the profile is selected, domains are added to the base's
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct Profile {
+    pub manager: Option<Manager>,
+    pub server: Option<ServerInfo>,
+    #[serde(default)]
+    pub domains: BTreeMap<String, DomainInfo>,
+    /// Domains of the base config that the profile doesn't have
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_domains: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
 pub struct ConfigData {
     #[serde(skip)]
     pub config_path: PathBuf,
+    /// Set when the config file belongs to another user (i.e. `kakapo`) and has to be written through sudo
+    #[serde(skip)]
+    pub owner: Option<String>,
+    /// Set when the config was moved to kakapo's home, the daemon is set up with it
+    #[serde(skip)]
+    pub kakapo_home: Option<PathBuf>,
+    /// The instance being configured, see `config::instances`
+    #[serde(skip)]
+    pub instance: Option<String>,
     pub version: Version,
     pub manager: Option<Manager>,
+    pub admin: Option<AdminInfo>,
+    pub server: Option<ServerInfo>,
     pub domains: BTreeMap<String, DomainInfo>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// Selected with `--profile` or `KAKAPO_PROFILE`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
 }
 
 impl ConfigData {
 
-    fn to_file(&self) -> Result<(), String> {
+    /// The file as it is now, to write the changes into it rather than over it
+    fn existing_document(&self) -> Option<String> {
+        match fs::read_to_string(&self.config_path) {
+            Ok(existing) => Some(existing),
+            Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => utils::privileged_read(&self.config_path).ok(),
+            Err(_) => None,
+        }
+    }
 
-        let data = serde_yaml::to_string(self)
-            .map_err(|err| err.to_string())?;
+    fn format(path: &Path) -> Result<ConfigFormat, KakapoError> {
+        ConfigFormat::from_path(path)
+            .map_err(|err| KakapoError::Usage(format!("{}: {}", path.display(), err)))
+    }
+
+    /// Comments are only kept in yaml files, the other formats are written from scratch
+    fn render(&self) -> Result<String, KakapoError> {
+        let format = ConfigData::format(&self.config_path)?;
+
+        let existing = match format {
+            ConfigFormat::Yaml => self.existing_document(),
+            _ => None,
+        };
+
+        let updated = match existing {
+            Some(existing) => match document::update_document(&existing, self) {
+                Ok(updated) => Some(updated),
+                Err(err) => {
+                    warn!("Could not edit {} in place: {}", self.config_path.display(), &err);
+                    eprintln!("{}", Yellow.paint(format!(
+                        "Could not write the changes into {} ({}), the whole file is rewritten and its comments are lost",
+                        self.config_path.display(), err)));
+                    None
+                },
+            },
+            None => None,
+        };
 
-        fs::write(self.config_path.to_owned(), data)
-            .map_err(|err| err.to_string())
+        match updated {
+            Some(data) => Ok(data),
+            None => format.render(self)
+                .map_err(|err| KakapoError::Other(format!("Could not serialize the configuration: {}", err))),
+        }
+    }
+
+    pub fn to_file(&self) -> Result<(), KakapoError> {
+
+        let data = self.render()?;
+
+        match self.owner {
+            Some(ref owner) => utils::privileged_write(&self.config_path, &data, owner),
+            None => match fs::write(self.config_path.to_owned(), &data) {
+                Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                    // a new file belongs to whoever owns the directory
+                    let owned_by = if self.config_path.exists() {
+                        self.config_path.as_path()
+                    } else {
+                        self.config_path.parent().unwrap_or(&self.config_path)
+                    };
+
+                    utils::file_owner(owned_by)
+                        .and_then(|owner| utils::privileged_write(&self.config_path, &data, &owner))
+                },
+                result => result.map_err(|err| {
+                    KakapoError::io(&format!("Could not write {}", self.config_path.display()), err)
+                }),
+            },
+        }
     }
 
-    fn from_file(path: PathBuf) -> Result<Self, String> {
-        let config_data_str = fs::read_to_string(path.to_owned())
-            .map_err(|err| err.to_string())?;
+    pub fn from_file(path: PathBuf) -> Result<Self, KakapoError> {
+        let config_data_str = match fs::read_to_string(path.to_owned()) {
+            Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => utils::privileged_read(&path)?,
+            result => result.map_err(|err| KakapoError::io(&format!("Could not read {}", path.display()), err))?,
+        };
 
-        let data: ConfigData = serde_yaml::from_str(&config_data_str)
-            .map_err(|err| err.to_string())?;
+        let data: ConfigData = ConfigData::format(&path)?
+            .parse(&config_data_str)
+            .map_err(|message| KakapoError::ConfigParse { path: path.to_owned(), message })?;
 
         Ok(data.with_path(path))
     }
 
-    fn with_path(mut self, config_path: PathBuf) -> Self {
+    /// Merges all the config files, see `config::layers`. `config_path` is where the config gets saved
+    pub fn from_layers(files: &[PathBuf], config_path: PathBuf) -> Result<Self, KakapoError> {
+        let last_file = files
+            .last()
+            .ok_or_else(|| KakapoError::io("No configuration file found", io::Error::from(io::ErrorKind::NotFound)))?;
+
+        let layered = layers::load(files)?;
+        let data: ConfigData = serde_yaml::from_value(layered.value)
+            .map_err(|err| KakapoError::ConfigParse { path: last_file.to_owned(), message: err.to_string() })?;
+
+        Ok(data.with_path(config_path))
+    }
+
+    /// `from_layers`, or an empty config on a host that has no config file yet
+    pub fn from_layers_or_default(files: &[PathBuf], config_path: PathBuf) -> Result<Self, KakapoError> {
+        if files.is_empty() {
+            Ok(ConfigData::default().with_path(config_path))
+        } else {
+            ConfigData::from_layers(files, config_path)
+        }
+    }
+
+    /// Reads the config the way the command line asked for, with the profile applied
+    pub fn load(source: &ConfigSource) -> Result<Self, KakapoError> {
+        let mut config_data = ConfigData::from_layers(&source.files, source.path.to_owned())?;
+        config_data.instance = source.instance.to_owned();
+        match source.profile {
+            Some(ref profile) => config_data.with_profile(profile),
+            None => Ok(config_data),
+        }
+    }
+
+    pub fn with_profile(self, profile: &str) -> Result<Self, KakapoError> {
+        if !self.profiles.contains_key(profile) {
+            let profiles: Vec<_> = self.profiles.keys().cloned().collect();
+            return Err(KakapoError::Usage(format!("Unknown profile '{}', the profiles are: {}", profile, profiles.join(", "))));
+        }
+
+        Ok(self.profile_view(profile))
+    }
+
+    /// The config as the profile sees it, a profile that doesn't exist yet doesn't change anything
+    pub fn profile_view(&self, profile: &str) -> Self {
+        let mut view = self.to_owned();
+        if let Some(profile) = self.profiles.get(profile) {
+            if profile.manager.is_some() {
+                view.manager = profile.manager.to_owned();
+            }
+            if profile.server.is_some() {
+                view.server = profile.server.to_owned();
+            }
+            for name in &profile.removed_domains {
+                view.domains.remove(name);
+            }
+            view.domains.extend(profile.domains.to_owned());
+        }
+
+        view
+    }
+
+    /// Stores what differs between `view` and this config in the profile, the sections that profiles don't have
+    /// go to the base config
+    pub fn merge_profile(&self, profile: &str, view: ConfigData) -> Self {
+        let mut config = view.to_owned();
+        config.manager = self.manager.to_owned();
+        config.server = self.server.to_owned();
+        config.domains = self.domains.to_owned();
+        config.profiles = self.profiles.to_owned();
+
+        fn changed<T: PartialEq>(base: &Option<T>, value: Option<T>) -> Option<T> {
+            if *base == value { None } else { value }
+        }
+
+        let removed_domains = self.domains
+            .keys()
+            .filter(|name| !view.domains.contains_key(*name))
+            .cloned()
+            .collect();
+        let domains = view.domains
+            .into_iter()
+            .filter(|(name, domain)| self.domains.get(name) != Some(domain))
+            .collect();
+
+        config.profiles.insert(profile.to_string(), Profile {
+            manager: changed(&self.manager, view.manager),
+            server: changed(&self.server, view.server),
+            domains,
+            removed_domains,
+        });
+
+        config
+    }
+
+    /// The config the steps see, with the profile applied
+    fn view(&self, profile: Option<&str>) -> Self {
+        match profile {
+            Some(profile) => self.profile_view(profile),
+            None => self.to_owned(),
+        }
+    }
+
+    /// The whole config once the steps' changes to `view` are stored in the profile
+    fn merge_view(&self, profile: Option<&str>, view: ConfigData) -> Self {
+        match profile {
+            Some(profile) => self.merge_profile(profile, view),
+            None => view,
+        }
+    }
+
+    pub fn with_path(mut self, config_path: PathBuf) -> Self {
         self.config_path = config_path;
         self
     }
@@ -161,108 +687,551 @@ fn print_welcome() {
 
 enum ConfigureWhat {
     Everything,
-    One(String),
+    Selected(Vec<usize>),
 }
 
-type StepFunction = Box<Fn(ConfigData, bool) -> Result<ConfigData, Box<Error>>>;
+/// Where the wizard goes once a step is done
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Navigation {
+    Next,
+    Back,
+    Jump(usize),
+    Abort,
+}
+
+pub type StepResult = Result<(ConfigData, Navigation), Box<Error>>;
 
-fn all_steps() -> Vec<(&'static str, StepFunction)> {
+type StepFunction = Box<Fn(ConfigData) -> StepResult>;
+
+/// A step as `configure --list` prints it
+#[derive(Serialize)]
+struct StepStatus {
+    index: usize,
+    name: String,
+    description: &'static str,
+    configured: bool,
+    depends_on: Vec<&'static str>,
+}
+
+struct Step {
+    name: &'static str,
+    description: &'static str,
+    /// What the step adds to the configuration or to the system
+    produces: &'static str,
+    /// Steps that have to be configured before this one
+    depends_on: &'static [&'static str],
+    run: StepFunction,
+    is_configured: fn(&ConfigData) -> bool,
+}
+
+fn all_steps() -> Vec<Step> {
      vec![
-        ("create central database", Box::new(steps::create_central_database)),
-        ("setup admin account", Box::new(steps::setup_admin_account)),
-        ("setup server", Box::new(steps::setup_server)),
-        ("create kakapo user", Box::new(steps::create_kakapo_user)), //TODO: linux only
-        ("setup daemon", Box::new(steps::setup_daemon)), //TODO: linux only
-        ("manage domains", Box::new(steps::manage_domains)),
+        Step {
+            name: "create central database",
+            description: "Database where Kakapo keeps its own data",
+            produces: "the manager database connection",
+            depends_on: &[],
+            run: Box::new(steps::create_central_database),
+            is_configured: steps::has_central_database,
+        },
+        Step {
+            name: "setup admin account",
+            description: "First user with full access",
+            produces: "the admin account",
+            depends_on: &["create central database"],
+            run: Box::new(steps::setup_admin_account),
+            is_configured: steps::has_admin_account,
+        },
+        Step {
+            name: "setup server",
+            description: "Host, port and TLS of the web server",
+            produces: "the server section",
+            depends_on: &[],
+            run: Box::new(steps::setup_server),
+            is_configured: steps::has_server,
+        },
+        Step {
+            name: "create kakapo user",
+            description: "System user that runs the Kakapo process",
+            produces: "the kakapo system user",
+            depends_on: &[],
+            run: Box::new(steps::create_kakapo_user), //TODO: linux only
+            is_configured: steps::has_kakapo_user,
+        },
+        Step {
+            name: "setup daemon",
+            description: "Systemd service for the server",
+            produces: "the systemd service",
+            depends_on: &["setup server"],
+            run: Box::new(steps::setup_daemon), //TODO: linux only
+            is_configured: steps::has_daemon,
+        },
+        Step {
+            name: "manage domains",
+            description: "Databases and services Kakapo connects to",
+            produces: "the domains",
+            depends_on: &[],
+            run: Box::new(steps::manage_domains),
+            is_configured: steps::has_domains,
+        },
     ]
 }
 
-fn start_configure_all(what: ConfigureWhat, config_data: ConfigData) -> Result<ConfigData, String> {
+/// Finds a step by its index (starting at 1) or by its name, e.g. `3`, `setup server` or `setup_server`
+fn find_step(steps: &[Step], step: &str) -> Option<usize> {
+    let step_canonical = step
+        .trim()
+        .to_lowercase()
+        .replace("_", " ")
+        .replace("-", " ");
+
+    steps
+        .iter()
+        .enumerate()
+        .find(|(idx, x)| step_canonical == format!("{}", idx+1) || step_canonical == x.name)
+        .map(|(idx, _)| idx)
+}
+
+/// Used by clap to check the `--step` values
+pub fn validate_step(step: String) -> Result<(), String> {
+    let steps = all_steps();
+    match find_step(&steps, &step) {
+        Some(_) => Ok(()),
+        None => {
+            let step_names: Vec<_> = steps
+                .iter()
+                .enumerate()
+                .map(|(idx, x)| format!("{} ({})", x.name.replace(" ", "_"), idx+1))
+                .collect();
+            Err(format!("unknown step '{}', expected one of: {}", step, step_names.join(", ")))
+        },
+    }
+}
+
+/// Prints every step with its index, description and whether it has already been configured
+pub fn list_steps(config_path: PathBuf, output: OutputFormat) -> Result<(), KakapoError> {
+    let config_data = if config_path.exists() {
+        ConfigData::from_file(config_path)?
+    } else {
+        ConfigData::default().with_path(config_path)
+    };
+
+    let statuses: Vec<StepStatus> = all_steps()
+        .iter()
+        .enumerate()
+        .map(|(idx, step)| StepStatus {
+            index: idx + 1,
+            name: step.name.replace(" ", "_"),
+            description: step.description,
+            configured: (step.is_configured)(&config_data),
+            depends_on: step.depends_on.to_vec(),
+        })
+        .collect();
+
+    output::print(output, &statuses, || {
+        for step in &statuses {
+            let status = if step.configured {
+                Green.paint("configured")
+            } else {
+                Yellow.paint("not configured")
+            };
+
+            println!("{} {: <24} {: <44} {}",
+                     RGB(131, 221, 2).bold().paint(format!("({})", step.index)),
+                     &step.name,
+                     step.description,
+                     status);
+            if !step.depends_on.is_empty() {
+                println!("    needs: {}", step.depends_on.join(", "));
+            }
+        }
+    })
+}
+
+/// Offers to continue an interrupted wizard from the first step that wasn't completed
+fn resume_from_checkpoint(checkpoint: &Checkpoint, steps: &[Step]) -> Option<usize> {
+    let idx = steps
+        .iter()
+        .position(|step| !checkpoint.completed_steps.iter().any(|x| x == step.name))?;
+
+    let resume = Confirmation::with_theme(&get_theme())
+        .with_text(&format!("The last configuration was interrupted, resume from step ({}) {}?",
+                            idx+1, steps[idx].name.to_title_case()))
+        .interact()
+        .unwrap_or(false);
+
+    if resume {
+        Some(idx)
+    } else {
+        None
+    }
+}
+
+/// Asks whether a step that is already configured should run again
+fn reconfigure_step(step: &Step) -> bool {
+    Confirmation::with_theme(&get_theme())
+        .with_text(&format!("{} is already configured, configure it again?", step.produces.to_sentence_case()))
+        .default(false)
+        .interact()
+        .unwrap_or(false)
+}
+
+/// Adds the missing prerequisites of the selected steps, if the user agrees to configure them as well
+fn add_missing_dependencies(steps: &[Step], step_indices: Vec<usize>, config: &ConfigData) -> Result<Vec<usize>, KakapoError> {
+    let theme = get_theme();
+    let mut step_indices = step_indices;
+    let mut checked = 0;
+
+    while checked < step_indices.len() {
+        let step = &steps[step_indices[checked]];
+        checked += 1;
+
+        for dependency in step.depends_on {
+            let dependency_idx = find_step(steps, dependency)
+                .ok_or_else(|| KakapoError::Other(format!("Unknown step '{}'", dependency)))?;
+            let dependency_step = &steps[dependency_idx];
+
+            if step_indices.contains(&dependency_idx) || (dependency_step.is_configured)(config) {
+                continue;
+            }
+
+            let action = Select::with_theme(&theme)
+                .with_prompt(&format!("{} needs {}, which is not configured yet",
+                                      step.name.to_title_case(), dependency_step.produces))
+                .default(0)
+                .item(&format!("Run {} first", dependency_step.name.to_title_case()))
+                .item("Abort")
+                .interact()?;
+
+            if action != 0 {
+                return Err(KakapoError::PromptAborted);
+            }
+
+            step_indices.push(dependency_idx);
+        }
+    }
+
+    // steps are listed in dependency order, so they run sorted by index
+    step_indices.sort();
+    step_indices.dedup();
+
+    Ok(step_indices)
+}
+
+/// Runs the steps on the profile's view of `base`, returns the whole config with the changes merged back
+fn start_configure_all(what: ConfigureWhat, base: ConfigData, profile: Option<String>) -> Result<ConfigData, KakapoError> {
 
     let steps = all_steps();
-    let mut config = config_data;
+    let mut base = base;
+    let mut profile = profile;
+    let mut config = base.view(profile.as_ref().map(|profile| profile.as_str()));
 
-    for (idx, step) in steps.iter().enumerate() {
-        let (step_name, step_op) = step;
-        let step_name_capitalized = step_name.to_title_case();
+    match &what {
+        ConfigureWhat::Everything => {
+            // the config path may change during the wizard, the checkpoint stays where the wizard looks for it
+            let checkpoint_path = config.config_path.to_owned();
+            let mut completed_steps: Vec<String> = vec![];
+            let mut idx = 0;
+            // steps before this one were already shown, a step reached with Back or Jump always runs
+            let mut first_unvisited = 0;
+            let mut navigated = false;
 
+            if let Some(checkpoint) = checkpoint::load(&checkpoint_path) {
+                match resume_from_checkpoint(&checkpoint, &steps) {
+                    Some(first_incomplete) => {
+                        idx = first_incomplete;
+                        completed_steps = checkpoint.completed_steps.to_owned();
+                        // the checkpoint has the whole config, the profile it was configuring wins over --profile
+                        if checkpoint.profile != profile {
+                            let resumed = checkpoint.profile.as_ref().map(|profile| profile.as_str()).unwrap_or("base");
+                            println!("{}", Yellow.paint(format!("    Resuming the {} profile", resumed)));
+                        }
+                        profile = checkpoint.profile.to_owned();
+                        base = checkpoint.into_config();
+                        config = base.view(profile.as_ref().map(|profile| profile.as_str()));
+                    },
+                    None => checkpoint::remove(&checkpoint_path),
+                }
+            }
+
+            while idx < steps.len() {
+                let step = &steps[idx];
 
-        match &what {
-            ConfigureWhat::Everything => {
                 println!("\n\t{} {}\n",
                     RGB(131, 221, 2).bold().paint(&format!("({})", idx+1)),
-                    step_name_capitalized);
+                    step.name.to_title_case());
+
+                let is_first_pass = !navigated && idx >= first_unvisited;
+                first_unvisited = first_unvisited.max(idx + 1);
+                if is_first_pass && (step.is_configured)(&config) && !reconfigure_step(step) {
+                    idx += 1;
+                    continue;
+                }
+
+                let (new_config, navigation) = (step.run)(config)
+                    .map_err(KakapoError::from_boxed)?;
+                config = new_config;
+
+                if !completed_steps.iter().any(|x| x == step.name) {
+                    completed_steps.push(step.name.to_string());
+                }
+                let merged = base.merge_view(profile.as_ref().map(|profile| profile.as_str()), config.to_owned());
+                if let Err(err) = checkpoint::save(&checkpoint_path, &Checkpoint::new(&merged, &completed_steps, profile.to_owned())) {
+                    println!("{}", Yellow.paint(format!("Could not save the progress: {}", err)));
+                }
 
-                config = match (step_op)(config, true) {
-                    Ok(config) => config,
-                    Err(err) => {
-                        return Err(err.to_string());
-                    }
+                navigated = navigation != Navigation::Next;
+                idx = match navigation {
+                    Navigation::Next => idx + 1,
+                    Navigation::Back => idx.saturating_sub(1),
+                    Navigation::Jump(step_idx) => step_idx,
+                    Navigation::Abort => return Err(KakapoError::PromptAborted),
                 };
-                println!("new config: {:?}", &config);
-            },
-            ConfigureWhat::One(ref step_to_configure) => {
-                let step_to_configure_canonical = step_to_configure.to_lowercase().replace("_", " ");
-                let step_idx = format!("{}", idx+1);
-                let step_name = step_name.to_owned();
-
-                if step_to_configure_canonical == step_idx || step_to_configure_canonical == step_name {
-                    config = match (step_op)(config, false) {
-                        Ok(config) => config,
-                        Err(err) => {
-                            return Err(err.to_string());
+            }
+        },
+        ConfigureWhat::Selected(ref step_indices) => {
+            let mut queue: VecDeque<usize> = step_indices.iter().cloned().collect();
+
+            while let Some(idx) = queue.pop_front() {
+                let step = &steps[idx];
+
+                if step_indices.len() > 1 {
+                    println!("\n\t{} {}\n",
+                        RGB(131, 221, 2).bold().paint(&format!("({})", idx+1)),
+                        step.name.to_title_case());
+                }
+
+                let (new_config, navigation) = (step.run)(config)
+                    .map_err(KakapoError::from_boxed)?;
+                config = new_config;
+
+                match navigation {
+                    Navigation::Next => {},
+                    Navigation::Back => {
+                        // come back to this step once the previous one is done
+                        queue.push_front(idx);
+                        if idx > 0 {
+                            queue.push_front(idx - 1);
                         }
-                    };
+                    },
+                    Navigation::Jump(step_idx) if step_idx < steps.len() => queue.push_front(step_idx),
+                    Navigation::Jump(_) => {},
+                    Navigation::Abort => return Err(KakapoError::PromptAborted),
                 }
-            },
-        }
+            }
+        },
     }
 
-    Ok(config)
+    Ok(base.merge_view(profile.as_ref().map(|profile| profile.as_str()), config))
 }
 
-pub fn get_possible_values() -> Vec<&'static str> {
-    let steps = all_steps();
-
-    steps
-        .into_iter()
-        .map(|x| x.0)
+pub fn get_step_names() -> Vec<&'static str> {
+    all_steps()
+        .iter()
+        .map(|step| step.name)
         .collect()
 }
 
-pub fn start_internal(reason: Reason, config_path: PathBuf) -> Result<ConfigData, String> {
-    match reason {
+/// With a profile, the steps see the config with the profile applied, and their changes end up in the profile
+pub fn start_internal(reason: Reason, source: &ConfigSource) -> Result<ConfigData, KakapoError> {
+    let config_path = source.path.to_owned();
+    let profile = source.profile.as_ref().map(|profile| profile.as_str());
+    let (selected_steps, mut config_data) = match reason {
         Reason::NoConfigFile => {
-            let config_data = ConfigData::default().with_path(config_path);
             print_welcome();
             println!("{}", Red.paint("    No Config file found, Starting the Configuration wizard"));
-            start_configure_all(ConfigureWhat::Everything, config_data)
+            (None, ConfigData::default().with_path(config_path))
         },
         Reason::InitialConfigure => {
-            let config_data = ConfigData::default().with_path(config_path);
             print_welcome();
-            start_configure_all(ConfigureWhat::Everything, config_data)
+            (None, ConfigData::default().with_path(config_path))
         },
         Reason::ReconfigureAll(config_file) => {
             let config_data = ConfigData::from_file(config_file)?;
             print_welcome();
-            start_configure_all(ConfigureWhat::Everything, config_data)
+            (None, config_data)
         },
-        Reason::Reconfigure(step, config_file) => {
-            let config_data = ConfigData::from_file(config_file)?;
-            start_configure_all(ConfigureWhat::One(step), config_data)
+        Reason::Reconfigure(selected_steps, config_file) => {
+            (Some(selected_steps), ConfigData::from_file(config_file)?)
         },
+    };
+    config_data.instance = source.instance.to_owned();
+
+    if let Some(ref instance) = source.instance {
+        println!("{}", Yellow.paint(format!("    Configuring the {} instance", instance)));
+    }
+
+    if let Some(profile) = profile {
+        println!("{}", Yellow.paint(format!("    Configuring the {} profile", profile)));
+    }
+    let view = config_data.view(profile);
+
+    let what = match selected_steps {
+        Some(selected_steps) => {
+            let steps = all_steps();
+            let step_indices = selected_steps
+                .iter()
+                .map(|step| find_step(&steps, step).ok_or_else(|| KakapoError::Usage(format!("Unknown step '{}'", step))))
+                .collect::<Result<Vec<_>, _>>()?;
+            ConfigureWhat::Selected(add_missing_dependencies(&steps, step_indices, &view)?)
+        },
+        None => ConfigureWhat::Everything,
+    };
+
+    start_configure_all(what, config_data, source.profile.to_owned())
+}
+
+/// Shows what the wizard changed, and lets the user save, discard or amend a step before anything is written
+fn review_changes(old_data: &ConfigData, data: ConfigData, profile: Option<&str>) -> Result<Option<ConfigData>, KakapoError> {
+    let theme = get_theme();
+    let steps = all_steps();
+    let mut data = data;
+
+    loop {
+        println!("\n\t{}\n", Style::new().bold().paint("Review your configuration"));
+        if !review::print_changes(old_data, &data) {
+            println!("No changes");
+        }
+        println!("");
+
+        let action = Select::with_theme(&theme)
+            .with_prompt("What would you like to do?")
+            .default(0)
+            .item("Save")
+            .item("Discard")
+            .item("Amend a step")
+            .interact()?;
+
+        match action {
+            0 => return Ok(Some(data)),
+            1 => return Ok(None),
+            _ => {
+                let step_names: Vec<_> = steps
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, step)| format!("({}) {}", idx+1, step.name.to_title_case()))
+                    .collect();
+
+                let step_idx = Select::with_theme(&theme)
+                    .with_prompt("Which step?")
+                    .default(0)
+                    .items(&step_names)
+                    .interact()?;
+
+                match (steps[step_idx].run)(data.view(profile)) {
+                    Ok((new_data, _)) => data = data.merge_view(profile, new_data),
+                    Err(err) => println!("{}", Red.bold().paint(err.to_string())),
+                }
+            },
+        }
     }
 }
 
-pub fn start(reason: Reason, config_path: PathBuf) {
-    match start_internal(reason, config_path) {
-        Ok(data) => {
-            let result = data.to_file();
-            println!("result of printing to file: {:?}", &result);
+pub fn start(reason: Reason, source: &ConfigSource) -> Result<(), KakapoError> {
+    let config_path = source.path.to_owned();
+    let profile = source.profile.as_ref().map(|profile| profile.as_str());
+    let checkpoint_path = config_path.to_owned();
+    let old_data = if config_path.exists() {
+        ConfigData::from_file(config_path.to_owned()).unwrap_or_default()
+    } else {
+        ConfigData::default()
+    };
+
+    let result = start_internal(reason, source)
+        .and_then(|data| review_changes(&old_data, data, profile));
+
+    match result? {
+        Some(data) => {
+            data.to_file()?;
+            checkpoint::remove(&checkpoint_path);
+            println!("Configuration saved to {}", RGB(131, 221, 2).bold().paint(data.config_path.to_string_lossy()));
+        },
+        None => {
+            checkpoint::remove(&checkpoint_path);
+            println!("{}", Yellow.paint("Configuration discarded"));
         },
-        Err(err) => {
-            println!("{}", Red.bold().paint(err));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{ConfigData, DomainInfo, PostgresOptions};
+
+    fn postgres(host: &str) -> DomainInfo {
+        DomainInfo::Postgres {
+            host: host.to_string(),
+            port: 5432,
+            user: "kakapo".to_string(),
+            pass: "secret".to_string(),
+            database: "kakapo".to_string(),
+            options: PostgresOptions::default(),
+            ssh_tunnel: None,
         }
     }
-}
\ No newline at end of file
+
+    fn base() -> ConfigData {
+        let mut config = ConfigData::default();
+        config.domains.insert("sirocco".to_string(), postgres("localhost"));
+        config.domains.insert("lisa".to_string(), postgres("localhost"));
+        config
+    }
+
+    #[test]
+    fn keeps_only_the_changes_in_the_profile() {
+        let base = base();
+        let mut view = base.profile_view("staging");
+        view.domains.insert("sirocco".to_string(), postgres("staging.db"));
+
+        let merged = base.merge_profile("staging", view);
+        let profile = &merged.profiles["staging"];
+
+        assert_eq!(merged.domains, base.domains);
+        assert_eq!(profile.domains.keys().collect::<Vec<_>>(), vec!["sirocco"]);
+        assert!(profile.removed_domains.is_empty());
+        assert_eq!(merged.profile_view("staging").domains["sirocco"], postgres("staging.db"));
+    }
+
+    #[test]
+    fn records_removed_domains() {
+        let base = base();
+        let mut view = base.profile_view("staging");
+        view.domains.remove("lisa");
+
+        let merged = base.merge_profile("staging", view);
+        let staging = merged.profile_view("staging");
+
+        assert!(merged.domains.contains_key("lisa"));
+        assert_eq!(merged.profiles["staging"].removed_domains, vec!["lisa".to_string()]);
+        assert!(!staging.domains.contains_key("lisa"));
+        assert!(staging.domains.contains_key("sirocco"));
+    }
+
+    #[test]
+    fn adding_a_removed_domain_back_clears_the_removal() {
+        let base = base();
+        let mut view = base.profile_view("staging");
+        view.domains.remove("lisa");
+        let merged = base.merge_profile("staging", view);
+
+        let mut view = merged.profile_view("staging");
+        view.domains.insert("lisa".to_string(), postgres("localhost"));
+        let merged = merged.merge_profile("staging", view);
+
+        assert!(merged.profiles["staging"].removed_domains.is_empty());
+        assert!(merged.profiles["staging"].domains.is_empty());
+        assert_eq!(merged.profile_view("staging").domains, base.domains);
+    }
+
+    #[test]
+    fn starts_empty_without_a_config_file() {
+        let config = ConfigData::from_layers_or_default(&[], PathBuf::from("/home/kakapo/.kakapo/config.yaml")).unwrap();
+
+        assert!(config.domains.is_empty());
+        assert_eq!(config.config_path, PathBuf::from("/home/kakapo/.kakapo/config.yaml"));
+        assert!(ConfigData::from_layers(&[], PathBuf::from("/home/kakapo/.kakapo/config.yaml")).is_err());
+    }
+}
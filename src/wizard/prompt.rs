@@ -0,0 +1,349 @@
+use std::env;
+use std::fmt::{Debug, Display};
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use atty;
+use atty::Stream;
+use dialoguer;
+use dialoguer::theme::ColorfulTheme;
+use rpassword;
+
+/// Dialoguer redraws its prompts, which only works on a terminal that understands cursor movements.
+/// Everywhere else the prompts fall back to reading plain lines, which also allows piping the answers in
+pub fn has_cursor_control() -> bool {
+    atty::is(Stream::Stdin) &&
+        atty::is(Stream::Stdout) &&
+        env::var("TERM").map(|term| term != "dumb").unwrap_or(false)
+}
+
+fn read_line() -> io::Result<String> {
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "No more input"));
+    }
+
+    Ok(line.trim().to_string())
+}
+
+pub struct Confirmation<'a> {
+    theme: &'a ColorfulTheme,
+    text: String,
+    default: bool,
+}
+
+impl<'a> Confirmation<'a> {
+    pub fn with_theme(theme: &'a ColorfulTheme) -> Self {
+        Confirmation {
+            theme,
+            text: String::new(),
+            default: true,
+        }
+    }
+
+    pub fn with_text(&mut self, text: &str) -> &mut Self {
+        self.text = text.to_string();
+        self
+    }
+
+    pub fn default(&mut self, default: bool) -> &mut Self {
+        self.default = default;
+        self
+    }
+
+    pub fn interact(&self) -> io::Result<bool> {
+        if has_cursor_control() {
+            return dialoguer::Confirmation::with_theme(self.theme)
+                .with_text(&self.text)
+                .default(self.default)
+                .interact();
+        }
+
+        let hint = if self.default { "[Y/n]" } else { "[y/N]" };
+        loop {
+            print!("{} {} ", &self.text, hint);
+            match read_line()?.to_lowercase().as_str() {
+                "" => return Ok(self.default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => println!("Please answer yes or no"),
+            }
+        }
+    }
+}
+
+pub struct Input<'a, T> {
+    theme: &'a ColorfulTheme,
+    prompt: String,
+    default: Option<T>,
+}
+
+impl<'a, T> Input<'a, T>
+    where
+        T: Clone + FromStr + Display,
+        T::Err: Display + Debug,
+{
+    pub fn with_theme(theme: &'a ColorfulTheme) -> Self {
+        Input {
+            theme,
+            prompt: String::new(),
+            default: None,
+        }
+    }
+
+    pub fn with_prompt(&mut self, prompt: &str) -> &mut Self {
+        self.prompt = prompt.to_string();
+        self
+    }
+
+    pub fn default(&mut self, value: T) -> &mut Self {
+        self.default = Some(value);
+        self
+    }
+
+    pub fn interact(&self) -> io::Result<T> {
+        if has_cursor_control() {
+            let mut input = dialoguer::Input::with_theme(self.theme);
+            input.with_prompt(&self.prompt);
+            if let Some(ref default) = self.default {
+                input.default(default.clone());
+            }
+            return input.interact();
+        }
+
+        loop {
+            match self.default {
+                Some(ref default) => print!("{} [{}]: ", &self.prompt, default),
+                None => print!("{}: ", &self.prompt),
+            }
+
+            let line = read_line()?;
+            if line.is_empty() {
+                if let Some(ref default) = self.default {
+                    return Ok(default.clone());
+                }
+                continue;
+            }
+
+            match line.parse::<T>() {
+                Ok(value) => return Ok(value),
+                Err(err) => println!("{}", err),
+            }
+        }
+    }
+}
+
+pub struct PasswordInput<'a> {
+    theme: &'a ColorfulTheme,
+    prompt: String,
+    confirmation: Option<(String, String)>,
+}
+
+impl<'a> PasswordInput<'a> {
+    pub fn with_theme(theme: &'a ColorfulTheme) -> Self {
+        PasswordInput {
+            theme,
+            prompt: String::new(),
+            confirmation: None,
+        }
+    }
+
+    pub fn with_prompt(&mut self, prompt: &str) -> &mut Self {
+        self.prompt = prompt.to_string();
+        self
+    }
+
+    pub fn with_confirmation(&mut self, prompt: &str, mismatch_err: &str) -> &mut Self {
+        self.confirmation = Some((prompt.to_string(), mismatch_err.to_string()));
+        self
+    }
+
+    fn read_password(prompt: &str) -> io::Result<String> {
+        let prompt = format!("{}: ", prompt);
+        if atty::is(Stream::Stdin) {
+            rpassword::prompt_password_stdout(&prompt)
+        } else {
+            print!("{}", prompt);
+            read_line()
+        }
+    }
+
+    pub fn interact(&self) -> io::Result<String> {
+        if has_cursor_control() {
+            let mut input = dialoguer::PasswordInput::with_theme(self.theme);
+            input.with_prompt(&self.prompt);
+            if let Some((ref prompt, ref mismatch_err)) = self.confirmation {
+                input.with_confirmation(prompt, mismatch_err);
+            }
+            return input.interact();
+        }
+
+        loop {
+            let password = PasswordInput::read_password(&self.prompt)?;
+            match self.confirmation {
+                Some((ref prompt, ref mismatch_err)) => {
+                    if PasswordInput::read_password(prompt)? == password {
+                        return Ok(password);
+                    }
+                    println!("{}", mismatch_err);
+                },
+                None => return Ok(password),
+            }
+        }
+    }
+}
+
+pub struct Select<'a> {
+    theme: &'a ColorfulTheme,
+    prompt: String,
+    default: usize,
+    items: Vec<String>,
+}
+
+impl<'a> Select<'a> {
+    pub fn with_theme(theme: &'a ColorfulTheme) -> Self {
+        Select {
+            theme,
+            prompt: String::new(),
+            default: 0,
+            items: vec![],
+        }
+    }
+
+    pub fn with_prompt(&mut self, prompt: &str) -> &mut Self {
+        self.prompt = prompt.to_string();
+        self
+    }
+
+    pub fn default(&mut self, default: usize) -> &mut Self {
+        self.default = default;
+        self
+    }
+
+    pub fn item(&mut self, item: &str) -> &mut Self {
+        self.items.push(item.to_string());
+        self
+    }
+
+    pub fn items<T: ToString>(&mut self, items: &[T]) -> &mut Self {
+        for item in items {
+            self.items.push(item.to_string());
+        }
+        self
+    }
+
+    pub fn interact(&self) -> io::Result<usize> {
+        if has_cursor_control() {
+            return dialoguer::Select::with_theme(self.theme)
+                .with_prompt(&self.prompt)
+                .default(self.default)
+                .items(&self.items)
+                .interact();
+        }
+
+        println!("{}", &self.prompt);
+        for (idx, item) in self.items.iter().enumerate() {
+            println!("  {}) {}", idx+1, item);
+        }
+
+        loop {
+            print!("Choice [{}]: ", self.default+1);
+
+            let line = read_line()?;
+            if line.is_empty() {
+                return Ok(self.default);
+            }
+
+            match line.parse::<usize>() {
+                Ok(choice) if choice >= 1 && choice <= self.items.len() => return Ok(choice-1),
+                _ => println!("Please enter a number between 1 and {}", self.items.len()),
+            }
+        }
+    }
+}
+
+pub struct Checkboxes<'a> {
+    theme: &'a ColorfulTheme,
+    prompt: String,
+    items: Vec<String>,
+}
+
+/// `1,3-5`, `all` or nothing, as indices
+fn parse_choices(line: &str, count: usize) -> Option<Vec<usize>> {
+    if line == "all" {
+        return Some((0..count).collect());
+    }
+
+    let mut choices = vec![];
+    for part in line.split(',').map(|part| part.trim()).filter(|part| !part.is_empty()) {
+        let mut bounds = part.splitn(2, '-').map(|bound| bound.trim().parse::<usize>());
+        let start = bounds.next()?.ok()?;
+        let end = match bounds.next() {
+            Some(end) => end.ok()?,
+            None => start,
+        };
+
+        if start < 1 || end > count || start > end {
+            return None;
+        }
+        choices.extend(start-1..end);
+    }
+
+    choices.sort();
+    choices.dedup();
+    Some(choices)
+}
+
+impl<'a> Checkboxes<'a> {
+    pub fn with_theme(theme: &'a ColorfulTheme) -> Self {
+        Checkboxes {
+            theme,
+            prompt: String::new(),
+            items: vec![],
+        }
+    }
+
+    pub fn with_prompt(&mut self, prompt: &str) -> &mut Self {
+        self.prompt = prompt.to_string();
+        self
+    }
+
+    pub fn item(&mut self, item: &str) -> &mut Self {
+        self.items.push(item.to_string());
+        self
+    }
+
+    pub fn items<T: ToString>(&mut self, items: &[T]) -> &mut Self {
+        for item in items {
+            self.items.push(item.to_string());
+        }
+        self
+    }
+
+    /// The indices of the checked items
+    pub fn interact(&self) -> io::Result<Vec<usize>> {
+        if has_cursor_control() {
+            return dialoguer::Checkboxes::with_theme(self.theme)
+                .with_prompt(&self.prompt)
+                .items(&self.items)
+                .interact();
+        }
+
+        println!("{}", &self.prompt);
+        for (idx, item) in self.items.iter().enumerate() {
+            println!("  {}) {}", idx+1, item);
+        }
+
+        loop {
+            print!("Choices (e.g. 1,3-5 or all, nothing for none): ");
+
+            match parse_choices(&read_line()?, self.items.len()) {
+                Some(choices) => return Ok(choices),
+                None => println!("Please enter numbers between 1 and {}", self.items.len()),
+            }
+        }
+    }
+}
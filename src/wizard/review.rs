@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+
+use ansi_term::Color::{Green, Red, Yellow};
+use serde_yaml::Value;
+
+use config::keys::{join, key_string};
+use output::{is_secret, REDACTED};
+use wizard::ConfigData;
+
+struct Entry {
+    value: String,
+    secret: bool,
+}
+
+impl Entry {
+    fn display(&self) -> &str {
+        if self.secret && !self.value.is_empty() {
+            REDACTED
+        } else {
+            self.value.as_str()
+        }
+    }
+}
+
+fn scalar_to_string(value: &Value) -> String {
+    match value {
+        Value::Null => "~".to_string(),
+        Value::Bool(x) => x.to_string(),
+        Value::Number(x) => x.to_string(),
+        Value::String(x) => x.to_owned(),
+        _ => String::new(),
+    }
+}
+
+/// Turns the config into `dotted.path => value` entries
+fn flatten(value: &Value, path: &str, secret: bool, entries: &mut BTreeMap<String, Entry>) {
+    match value {
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping {
+                let key = key_string(key);
+                flatten(value, &join(path, &key), secret || is_secret(&key), entries);
+            }
+        },
+        // i.e. `removed_domains`, keyed by the domain name so removing one doesn't shift the others
+        Value::Sequence(sequence) => {
+            for (idx, value) in sequence.iter().enumerate() {
+                let key = match value {
+                    Value::Mapping(_) | Value::Sequence(_) => idx.to_string(),
+                    scalar => scalar_to_string(scalar),
+                };
+                flatten(value, &join(path, &key), secret, entries);
+            }
+        },
+        scalar => {
+            entries.insert(path.to_string(), Entry { value: scalar_to_string(scalar), secret });
+        },
+    }
+}
+
+fn entries(config: &ConfigData) -> BTreeMap<String, Entry> {
+    let mut entries = BTreeMap::new();
+    if let Ok(value) = serde_yaml::to_value(config) {
+        flatten(&value, "", false, &mut entries);
+    }
+
+    entries
+}
+
+#[derive(Debug, PartialEq)]
+enum Change {
+    Removed { path: String, value: String },
+    Changed { path: String, old: String, new: String },
+    Added { path: String, value: String },
+}
+
+/// The differences between the two configs, with the secrets hidden
+fn changes(old: &ConfigData, new: &ConfigData) -> Vec<Change> {
+    let old_entries = entries(old);
+    let new_entries = entries(new);
+    let mut changes = vec![];
+
+    for (path, old_entry) in &old_entries {
+        match new_entries.get(path) {
+            None => changes.push(Change::Removed { path: path.to_owned(), value: old_entry.display().to_string() }),
+            Some(new_entry) if new_entry.value != old_entry.value => {
+                let new_value = if new_entry.secret { "***** (changed)" } else { new_entry.display() };
+                changes.push(Change::Changed {
+                    path: path.to_owned(),
+                    old: old_entry.display().to_string(),
+                    new: new_value.to_string(),
+                });
+            },
+            _ => {},
+        }
+    }
+
+    for (path, new_entry) in &new_entries {
+        if !old_entries.contains_key(path) {
+            changes.push(Change::Added { path: path.to_owned(), value: new_entry.display().to_string() });
+        }
+    }
+
+    changes
+}
+
+/// Prints what changed between the two configs, with the secrets hidden. Returns false if nothing changed
+pub fn print_changes(old: &ConfigData, new: &ConfigData) -> bool {
+    let changes = changes(old, new);
+
+    for change in &changes {
+        match change {
+            Change::Removed { path, value } => println!("{}", Red.paint(format!("- {}: {}", path, value))),
+            Change::Changed { path, old, new } => println!("{}", Yellow.paint(format!("~ {}: {} -> {}", path, old, new))),
+            Change::Added { path, value } => println!("{}", Green.paint(format!("+ {}: {}", path, value))),
+        }
+    }
+
+    !changes.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use wizard::{AdminInfo, ConfigData, DomainInfo, PostgresOptions, Profile};
+
+    use super::{changes, Change};
+
+    fn postgres(host: &str, pass: &str) -> DomainInfo {
+        DomainInfo::Postgres {
+            host: host.to_string(),
+            port: 5432,
+            user: "kakapo".to_string(),
+            pass: pass.to_string(),
+            database: "kakapo".to_string(),
+            options: PostgresOptions::default(),
+            ssh_tunnel: None,
+        }
+    }
+
+    fn with_domains(domains: Vec<(&str, DomainInfo)>) -> ConfigData {
+        let mut config = ConfigData::default();
+        config.domains = domains.into_iter().map(|(name, domain)| (name.to_string(), domain)).collect();
+        config
+    }
+
+    fn with_removed_domains(removed_domains: &[&str]) -> ConfigData {
+        let mut config = ConfigData::default();
+        let profile = Profile {
+            removed_domains: removed_domains.iter().map(|name| name.to_string()).collect(),
+            ..Profile::default()
+        };
+        config.profiles.insert("staging".to_string(), profile);
+        config
+    }
+
+    #[test]
+    fn lists_added_removed_and_changed_entries() {
+        let old = with_domains(vec![("billing", postgres("db1", "secret")), ("users", postgres("db2", "secret"))]);
+        let new = with_domains(vec![("billing", postgres("db3", "secret")), ("orders", postgres("db2", "secret"))]);
+
+        let changes = changes(&old, &new);
+
+        assert!(changes.contains(&Change::Changed {
+            path: "domains.billing.host".to_string(),
+            old: "db1".to_string(),
+            new: "db3".to_string(),
+        }));
+        assert!(changes.contains(&Change::Removed { path: "domains.users.host".to_string(), value: "db2".to_string() }));
+        assert!(changes.contains(&Change::Added { path: "domains.orders.host".to_string(), value: "db2".to_string() }));
+        assert!(!changes.iter().any(|change| match change {
+            Change::Changed { path, .. } => path.as_str() != "domains.billing.host",
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn hides_the_secrets() {
+        let old = with_domains(vec![("billing", postgres("db1", "old secret")), ("users", postgres("db2", "secret"))]);
+        let mut new = with_domains(vec![("billing", postgres("db1", "new secret")), ("orders", postgres("db2", "secret"))]);
+        new.admin = Some(AdminInfo {
+            user: "admin".to_string(),
+            pass_hash: "pbkdf2-sha256$100000$c2FsdA==$aGFzaA==".to_string(),
+            email: "admin@example.com".to_string(),
+            display_name: "Admin".to_string(),
+        });
+
+        let changes = changes(&old, &new);
+
+        assert!(changes.contains(&Change::Changed {
+            path: "domains.billing.pass".to_string(),
+            old: "*****".to_string(),
+            new: "***** (changed)".to_string(),
+        }));
+        assert!(changes.contains(&Change::Removed { path: "domains.users.pass".to_string(), value: "*****".to_string() }));
+        assert!(changes.contains(&Change::Added { path: "domains.orders.pass".to_string(), value: "*****".to_string() }));
+        assert!(changes.contains(&Change::Added { path: "admin.pass_hash".to_string(), value: "*****".to_string() }));
+        assert!(!format!("{:?}", changes).contains("secret"));
+    }
+
+    #[test]
+    fn keys_the_removed_domains_by_name() {
+        let old = with_removed_domains(&["billing", "orders", "users"]);
+        let new = with_removed_domains(&["orders", "users"]);
+
+        assert_eq!(changes(&old, &new), vec![Change::Removed {
+            path: "profiles.staging.removed_domains.billing".to_string(),
+            value: "billing".to_string(),
+        }]);
+    }
+}
@@ -6,21 +6,45 @@ use std::process::Command;
 use std::process::Stdio;
 use std::str::from_utf8;
 use std::path::PathBuf;
+use std::path::Path;
+use std::fs;
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::env;
 use std::collections::BTreeMap;
 
 use console::Style;
-use dialoguer::{theme::ColorfulTheme, Confirmation, Input, PasswordInput, Select};
+use dialoguer::theme::ColorfulTheme;
 use ansi_term::Color::{Green, Yellow, Red, RGB};
+use inflector::Inflector;
 
 use wizard::ConfigData;
-use wizard::Manager;
+use wizard::Navigation;
+use wizard::StepResult;
+use wizard::get_step_names;
+use wizard::print_domains;
+use wizard::AdminInfo;
 use wizard::utils;
+use wizard::prompt::{Checkboxes, Confirmation, Input, PasswordInput, Select};
+use wizard::connection;
+use wizard::connection::PostgresConnection;
 use wizard::DomainInfo;
+use wizard::SshTunnel;
+use wizard::ServerInfo;
+use wizard::TlsInfo;
 
+use config;
 use config::CONFIG_YAML;
+use config::instances;
+use tls;
+use tls::acme;
+use tunnel;
+use error::KakapoError;
+use commands::domain;
+
+const SYSTEMD_UNIT_DIR: &'static str = "/etc/systemd/system";
+/// Shared by the named instances, which get it as `kakapo@NAME.service`
+const SYSTEMD_TEMPLATE_UNIT: &'static str = "kakapo@.service";
 
 pub fn get_theme() -> ColorfulTheme {
     ColorfulTheme {
@@ -35,19 +59,43 @@ pub fn get_theme() -> ColorfulTheme {
     }
 }
 
-pub fn create_central_database(data: ConfigData, check_if_exists: bool) -> Result<ConfigData, Box<Error>> {
-    let theme = get_theme();
+/// Asks where to go once a step is done
+fn navigate(theme: &ColorfulTheme, data: ConfigData) -> StepResult {
+    let action = Select::with_theme(theme)
+        .with_prompt("What next?")
+        .default(0)
+        .item("Continue")
+        .item("Go back to the previous step")
+        .item("Jump to another step")
+        .item("Abort")
+        .interact()?;
+
+    let navigation = match action {
+        0 => Navigation::Next,
+        1 => Navigation::Back,
+        2 => {
+            let step_names: Vec<_> = get_step_names()
+                .iter()
+                .enumerate()
+                .map(|(idx, step_name)| format!("({}) {}", idx+1, step_name.to_title_case()))
+                .collect();
+
+            let step_idx = Select::with_theme(theme)
+                .with_prompt("Which step?")
+                .default(0)
+                .items(&step_names)
+                .interact()?;
+
+            Navigation::Jump(step_idx)
+        },
+        _ => Navigation::Abort,
+    };
 
-    if check_if_exists && data.manager.is_some() {
-        let continue_step = Confirmation::with_theme(&theme)
-            .with_text("Continue?")
-            .interact()
-            .unwrap_or(false);
+    Ok((data, navigation))
+}
 
-        if !continue_step {
-            return Ok(data)
-        }
-    }
+pub fn create_central_database(data: ConfigData) -> StepResult {
+    let theme = get_theme();
 
     let use_postgres = Confirmation::with_theme(&theme)
         .with_text("Choose default database. Currently the only option is postgres. Do you want to continue?")
@@ -57,46 +105,22 @@ pub fn create_central_database(data: ConfigData, check_if_exists: bool) -> Resul
         return Err("Database type is not available".to_string().into());
     }
 
-    let host: String = Input::with_theme(&theme)
-        .with_prompt("Database host?")
-        .default("127.0.0.1".to_string())
-        .interact()?;
-
-    let port: u16 = Input::with_theme(&theme)
-        .with_prompt("Database port?")
-        .default(5432)
-        .interact()?;
-
-    let user: String = Input::with_theme(&theme)
-        .with_prompt("Database username?")
-        .interact()?;
-
-    let pass: String = PasswordInput::with_theme(&theme)
-        .with_prompt("Database password?")
-        .interact()?;
-
-    let database: String = Input::with_theme(&theme)
-        .with_prompt("Default database?")
-        .default(user.to_owned())
-        .interact()?;
+    let manager = connection::prompt(&theme)?.into_manager();
 
-    println!("postgres://{}:{}@{}:{}/{}", user, pass, host, port, database);
+    println!("postgres://{}:*****@{}:{}/{}", manager.user, manager.host, manager.port, manager.database);
     //TODO: test connection
 
     let mut new_data = data.to_owned();
-    let manager = Manager {
-        db_type: "postgres".to_string(),
-        host, port,
-        user, pass,
-        database
-    };
-
     new_data.manager = Some(manager);
 
-    Ok(new_data)
+    navigate(&theme, new_data)
+}
+
+pub fn has_central_database(data: &ConfigData) -> bool {
+    data.manager.is_some()
 }
 
-pub fn setup_admin_account(data: ConfigData, check_if_exists: bool) -> Result<ConfigData, Box<Error>> {
+pub fn setup_admin_account(data: ConfigData) -> StepResult {
     let theme = get_theme();
 
     //TODO: check if user exists db::get_admin_user()
@@ -109,6 +133,8 @@ pub fn setup_admin_account(data: ConfigData, check_if_exists: bool) -> Result<Co
         .with_prompt("Admin password?")
         .with_confirmation("Repeat password", "Error: the passwords don't match.")
         .interact()?;
+    let pass_hash = utils::hash_password(&pass)
+        .map_err(|err| KakapoError::Other(format!("Could not hash the admin password: {}", err)))?;
 
     let email: String = Input::with_theme(&theme)
         .with_prompt("Admin Email?")
@@ -119,49 +145,201 @@ pub fn setup_admin_account(data: ConfigData, check_if_exists: bool) -> Result<Co
         .default(user.to_owned())
         .interact()?;
 
-    //TODO: build config data
-    Ok(data)
+    let mut new_data = data.to_owned();
+    new_data.admin = Some(AdminInfo {
+        user,
+        pass_hash,
+        email,
+        display_name,
+    });
+
+    navigate(&theme, new_data)
+}
+
+pub fn has_admin_account(data: &ConfigData) -> bool {
+    data.admin.is_some()
 }
 
-pub fn setup_server(data: ConfigData, check_if_exists: bool) -> Result<ConfigData, Box<Error>> {
+pub fn setup_server(data: ConfigData) -> StepResult {
     let theme = get_theme();
-    /*
-    if check_if_exists && data.server.is_some() {
-        let continue_step = Confirmation::with_theme(&theme)
-            .with_text("Continue?")
-            .interact()
-            .unwrap_or(false);
-
-        if !continue_step {
-            return Ok(data)
-        }
-    }
-    */
 
     let server_name: String = Input::with_theme(&theme)
         .with_prompt("What is your server host (e.g. www.kakapo.ai)")
         .interact()?;
 
-    let default_port: u16 = Input::with_theme(&theme)
-        .with_prompt("Which port to run the server on?")
-        .default(1845)
+    let instance = data.instance.as_ref().map(|instance| instance.as_str());
+    let default_port = loop {
+        let port: u16 = Input::with_theme(&theme)
+            .with_prompt("Which port to run the server on?")
+            .default(instances::DEFAULT_PORT)
+            .interact()?;
+
+        // a broken config in another instance shouldn't stop this one from being configured
+        let collisions = instances::port_collisions(instance, port).unwrap_or_default();
+        if collisions.is_empty() {
+            break port;
+        }
+
+        let names: Vec<_> = collisions
+            .iter()
+            .map(|name| instances::display_name(name.as_ref().map(|name| name.as_str())))
+            .collect();
+        println!("{}", Yellow.paint(format!("Port {} is already used by the {} instance", port, names.join(", "))));
+
+        let use_anyway = Confirmation::with_theme(&theme)
+            .with_text("Use it anyway?")
+            .default(false)
+            .interact()?;
+        if use_anyway {
+            break port;
+        }
+    };
+
+    let tls = if cfg!(feature = "server-tls") {
+        Select::with_theme(&theme)
+            .with_prompt("Configure TLS")
+            .default(0)
+            .item("Setup with Let's Encrypt")
+            .item("manual")
+            .item("self-signed")
+            .item("no")
+            .interact()?
+    } else {
+        println!("This build of kakapo serves plain HTTP, put a reverse proxy in front of it for HTTPS \
+                  (TLS needs the {} feature)", RGB(131, 221, 2).bold().paint("server-tls"));
+        3
+    };
+
+    let tls_info = match tls {
+        0 => Some(setup_lets_encrypt(&theme, &server_name, instance)?),
+        1 => Some(setup_manual_tls(&theme)?),
+        2 => {
+            // each instance has its own certificate
+            let mut tls_dir = config::get_config_dir(instance)?;
+            tls_dir.push(tls::TLS_DIR);
+
+            let (cert_path, key_path) = tls::generate_self_signed(&server_name, &tls_dir)?;
+            println!("Generated a self-signed certificate in {}", RGB(131, 221, 2).bold().paint(tls_dir.to_string_lossy()));
+
+            Some(TlsInfo::SelfSigned { cert_path, key_path })
+        },
+        _ => None,
+    };
+
+    let mut server = ServerInfo {
+        host: server_name,
+        port: default_port,
+        bind: None,
+        tls: tls_info,
+    };
+    let bind: String = Input::with_theme(&theme)
+        .with_prompt("Which address should the server listen on?")
+        .default(server.bind_address().to_string())
         .interact()?;
+    if bind != server.bind_address() {
+        server.bind = Some(bind);
+    }
 
-    let tls = Select::with_theme(&theme)
-        .with_prompt("Configure TLS")
-        .default(0)
-        .item("Setup with Let's Encrypt")
-        .item("manual")
-        .item("no")
+    let mut new_data = data.to_owned();
+    new_data.server = Some(server);
+
+    navigate(&theme, new_data)
+}
+
+fn setup_lets_encrypt(theme: &ColorfulTheme, server_name: &str, instance: Option<&str>) -> Result<TlsInfo, Box<Error>> {
+    let email: String = Input::with_theme(theme)
+        .with_prompt("Contact email for Let's Encrypt?")
         .interact()?;
 
-    //TODO: set up tls if true
+    let directory_url: String = Input::with_theme(theme)
+        .with_prompt("ACME directory URL?")
+        .default(acme::LETS_ENCRYPT_DIRECTORY.to_string())
+        .interact()?;
+
+    let http_port: u16 = Input::with_theme(theme)
+        .with_prompt("Port to answer the HTTP challenge on?")
+        .default(80)
+        .interact()?;
+
+    let directory_ca = if directory_url == acme::LETS_ENCRYPT_DIRECTORY {
+        None
+    } else {
+        let directory_ca: String = Input::with_theme(theme)
+            .with_prompt("CA of the ACME directory's certificate, e.g. Pebble's (empty for the system's CAs)?")
+            .default(String::new())
+            .interact()?;
+        if directory_ca.is_empty() { None } else { Some(PathBuf::from(directory_ca)) }
+    };
+
+    // the certificate and the ACME account belong to the instance
+    let mut tls_dir = config::get_config_dir(instance)?;
+    tls_dir.push(tls::TLS_DIR);
+
+    let settings = acme::AcmeSettings {
+        host: server_name.to_string(),
+        email: email.to_owned(),
+        directory_url: directory_url.to_owned(),
+        http_port,
+        directory_ca: directory_ca.to_owned(),
+        tls_dir,
+    };
+
+    if let Some(ref directory_ca) = directory_ca {
+        acme::trust_directory_ca(directory_ca);
+    }
+
+    println!("Requesting a certificate for {}...", RGB(131, 221, 2).bold().paint(server_name));
+    let (cert_path, key_path) = acme::obtain_certificate(&settings)
+        .map_err(|err| KakapoError::ConnectionFailed { target: settings.directory_url.to_owned(), message: err })?;
+    println!("Certificate stored in {}", RGB(131, 221, 2).bold().paint(cert_path.to_string_lossy()));
 
-    Ok(data)
+    Ok(TlsInfo::LetsEncrypt { email, directory_url, http_port, directory_ca, cert_path, key_path })
+}
+
+fn setup_manual_tls(theme: &ColorfulTheme) -> Result<TlsInfo, Box<Error>> {
+    loop {
+        let cert_path: String = Input::with_theme(theme)
+            .with_prompt("Path to the certificate (PEM)?")
+            .interact()?;
+
+        let key_path: String = Input::with_theme(theme)
+            .with_prompt("Path to the private key (PEM)?")
+            .interact()?;
+
+        let cert_path = PathBuf::from(cert_path);
+        let key_path = PathBuf::from(key_path);
+
+        match tls::validate_certificate(&cert_path, &key_path) {
+            Ok(info) => {
+                println!("Certificate for {} is valid for {} more days",
+                         RGB(131, 221, 2).bold().paint(info.common_name), info.days_left);
+                if info.days_left < 30 {
+                    println!("{}", Yellow.paint("The certificate will expire soon, don't forget to renew it"));
+                }
+
+                return Ok(TlsInfo::Manual { cert_path, key_path });
+            },
+            Err(err) => {
+                println!("{}", Red.paint(err));
+
+                let try_again = Confirmation::with_theme(theme)
+                    .with_text("Try again?")
+                    .interact()?;
+
+                if !try_again {
+                    return Err("Could not set up TLS".to_string().into());
+                }
+            },
+        }
+    }
+}
+
+pub fn has_server(data: &ConfigData) -> bool {
+    data.server.is_some()
 }
 
 //TODO: linux only
-pub fn create_kakapo_user(data: ConfigData, check_if_exists: bool) -> Result<ConfigData, Box<Error>> {
+pub fn create_kakapo_user(data: ConfigData) -> StepResult {
     let theme = get_theme();
 
     let create_user = Confirmation::with_theme(&theme)
@@ -169,7 +347,7 @@ pub fn create_kakapo_user(data: ConfigData, check_if_exists: bool) -> Result<Con
         .interact()?;
 
     if !create_user {
-        return Ok(data);
+        return Ok((data, Navigation::Next));
     }
 
     let result = Command::new("sudo")
@@ -185,7 +363,7 @@ pub fn create_kakapo_user(data: ConfigData, check_if_exists: bool) -> Result<Con
     if result.status.success() {
         println!("{} successfully created", RGB(131, 221, 2).bold().paint("kakapo"));
     } else {
-        return Err(Box::new(io::Error::new(io::ErrorKind::PermissionDenied, "Could not create user")));
+        return Err(Box::new(KakapoError::external_command("sudo adduser --system --quiet --group kakapo", &result.status.to_string())));
     }
 
     let kakapo_passwd = Command::new("sudo")
@@ -198,7 +376,15 @@ pub fn create_kakapo_user(data: ConfigData, check_if_exists: bool) -> Result<Con
     let kakapo_home = kakapo_passwd.get(5)
         .ok_or_else(|| Box::new(io::Error::new(io::ErrorKind::PermissionDenied, "Could not find kakapo home")))?;
     let mut kakapo_new_config_path = PathBuf::from(kakapo_home);
-    kakapo_new_config_path.push(CONFIG_YAML);
+    if let Some(ref instance) = data.instance {
+        kakapo_new_config_path = instances::get_instance_dir(&kakapo_new_config_path, instance);
+    }
+    // keep the format the config is already in
+    let config_file_name = data.config_path
+        .file_name()
+        .map(|file_name| file_name.to_owned())
+        .unwrap_or_else(|| CONFIG_YAML.into());
+    kakapo_new_config_path.push(config_file_name);
 
     let new_kakapo_home = Select::with_theme(&theme)
         .with_prompt("Would you like to change the location of the config file?")
@@ -209,7 +395,19 @@ pub fn create_kakapo_user(data: ConfigData, check_if_exists: bool) -> Result<Con
         .interact()?;
 
     let mut new_data = data.to_owned();
-    match new_kakapo_home { //TODO: figure out the permission here, the user must have access to config.yaml...
+    if new_kakapo_home < 2 {
+        new_data.kakapo_home = Some(PathBuf::from(kakapo_home));
+        // The config file will be owned by kakapo, the operator reads it through the kakapo group
+        let operator = utils::operator_name()?;
+        utils::add_user_to_group(&operator, "kakapo")?;
+        println!("{} was added to the {} group, log out and back in for this to take effect",
+                 &operator, RGB(131, 221, 2).bold().paint("kakapo"));
+        println!("The directory holding the config in kakapo's home will be readable by the {} group (chmod g+rx), \
+                  nothing else in that home changes", RGB(131, 221, 2).bold().paint("kakapo"));
+        new_data.owner = Some("kakapo".to_string());
+    }
+
+    match new_kakapo_home {
         0 => {
             new_data.config_path = kakapo_new_config_path;
             let mut profile_path = env::home_dir()
@@ -233,11 +431,95 @@ pub fn create_kakapo_user(data: ConfigData, check_if_exists: bool) -> Result<Con
         },
     }
 
-    Ok(new_data)
+    if new_kakapo_home < 2 {
+        let tls_info = new_data.server.as_ref().and_then(|server| server.tls.to_owned());
+        if let (Some(tls_info), Some(config_dir)) = (tls_info, new_data.config_path.parent()) {
+            let tls_info = relocate_tls(&tls_info, &config_dir.join(tls::TLS_DIR), "kakapo")?;
+            if let Some(ref mut server) = new_data.server {
+                server.tls = Some(tls_info);
+            }
+        }
+    }
+
+    navigate(&theme, new_data)
+}
+
+/// Moves the certificate and the key the wizard created next to the moved config, owned by the user the daemon runs
+/// as, a certificate the operator configured stays where it is
+fn relocate_tls(tls_info: &TlsInfo, tls_dir: &Path, owner: &str) -> Result<TlsInfo, KakapoError> {
+    let (cert_path, key_path) = tls_info.files();
+
+    if let TlsInfo::Manual { .. } = tls_info {
+        if !utils::can_read_as(owner, key_path) {
+            println!("{}", Yellow.paint(format!("{} can't read {}, the server won't start until it can", owner, key_path.display())));
+        }
+        return Ok(tls_info.to_owned());
+    }
+
+    let new_cert_path = tls_dir.join(tls::CERT_PEM);
+    let new_key_path = tls_dir.join(tls::KEY_PEM);
+    utils::privileged_copy(cert_path, &new_cert_path, "0644", owner)?;
+    utils::privileged_copy(key_path, &new_key_path, "0600", owner)?;
+
+    // no copy of the key should stay behind in the operator's home
+    fs::remove_file(key_path)
+        .map_err(|err| KakapoError::io(&format!("Could not remove {}", key_path.display()), err))?;
+    let _ = fs::remove_file(cert_path);
+
+    println!("Moved the certificate and the key to {}", RGB(131, 221, 2).bold().paint(tls_dir.to_string_lossy()));
+    Ok(tls_info.with_files(new_cert_path, new_key_path))
+}
+
+pub fn has_kakapo_user(_data: &ConfigData) -> bool {
+    Command::new("id")
+        .arg("-u")
+        .arg("kakapo")
+        .output()
+        .map(|result| result.status.success())
+        .unwrap_or(false)
+}
+
+//TODO: After=network.target postgresql.service mysql.service redis.service rabbitmq-server.service ?
+fn systemd_unit(is_template: bool, user: &str, kakapo_home: &Path, executable: &Path, bind_capability: bool) -> String {
+    let (description, instance_environment) = if is_template {
+        (" (%i)", "\nEnvironment=\"KAKAPO_INSTANCE=%i\"")
+    } else {
+        ("", "")
+    };
+    // the ACME challenge is answered on port 80 by default
+    let capabilities = if bind_capability {
+        "\nAmbientCapabilities=CAP_NET_BIND_SERVICE"
+    } else {
+        ""
+    };
+
+    format!(r#"[Unit]
+Description=Kakapo system service{description}
+After=network.target
+Wants=postgresql.service mysql.service redis.service rabbitmq-server.service
+
+[Service]
+Type=simple
+User={user}
+Group={user}
+Environment="KAKAPO_HOME={kakapo_home}"{instance_environment}
+ExecStart={executable}{capabilities}
+Restart=on-failure
+RestartSec=10s
+
+[Install]
+WantedBy=multi-user.target
+"#,
+        description = description,
+        user = user,
+        kakapo_home = kakapo_home.to_string_lossy(),
+        instance_environment = instance_environment,
+        executable = executable.to_string_lossy(),
+        capabilities = capabilities)
 }
 
 //TODO: linux only
-pub fn setup_daemon(data: ConfigData, check_if_exists: bool) -> Result<ConfigData, Box<Error>> {
+pub fn setup_daemon(data: ConfigData) -> StepResult {
     let theme = get_theme();
 
     let setup_systemd = Confirmation::with_theme(&theme)
@@ -245,7 +527,7 @@ pub fn setup_daemon(data: ConfigData, check_if_exists: bool) -> Result<ConfigDat
         .interact()?;
 
     if !setup_systemd {
-        return Ok(data);
+        return Ok((data, Navigation::Next));
     }
 
     let has_kakapo_user = Command::new("id")
@@ -262,47 +544,150 @@ pub fn setup_daemon(data: ConfigData, check_if_exists: bool) -> Result<ConfigDat
         .stdout;
     let current_user = from_utf8(&current_user)?.trim();
 
-    let systemd_user = if has_kakapo_user {
-        Select::with_theme(&theme)
-            .with_prompt("Which user should the daemon run as?")
-            .default(0)
-            .item("kakapo")
-            .item(&current_user)
-            .item("sudo")
-            .interact()?
-    } else {
-        Select::with_theme(&theme)
-            .with_prompt("Which user should the daemon run as?")
-            .default(0)
-            .item(&current_user) //TODO: check if current user is not sudo or kakapo
-            .item("sudo")
-            .interact()?
+    let mut users = vec![];
+    if has_kakapo_user {
+        users.push("kakapo");
+    }
+    users.push(current_user); //TODO: check if current user is not sudo or kakapo
+
+    let user_idx = Select::with_theme(&theme)
+        .with_prompt("Which user should the daemon run as?")
+        .default(0)
+        .items(&users)
+        .item("sudo")
+        .interact()?;
+    let systemd_user = users.get(user_idx).cloned().unwrap_or("root");
+
+    let kakapo_home = match data.kakapo_home {
+        Some(ref kakapo_home) => kakapo_home.to_owned(),
+        None => config::get_kakapo_home().map_err(KakapoError::Other)?,
+    };
+    // the daemon only finds its config through the unit's KAKAPO_HOME
+    let config_file = if data.config_path.exists() { data.config_path.as_path() } else { kakapo_home.as_path() };
+    if systemd_user != "root" && !utils::can_read_as(systemd_user, config_file) {
+        return Err(Box::new(KakapoError::Permission {
+            context: format!("{} can't read {}, pick another user or move the config", systemd_user, config_file.display()),
+        }));
+    }
+    let executable = env::current_exe()?;
+
+    let instance = data.instance.as_ref().map(|instance| instance.as_str());
+    let unit_file = match instance {
+        Some(_) => SYSTEMD_TEMPLATE_UNIT.to_string(),
+        None => instances::unit_name(None),
+    };
+    let unit_path = Path::new(SYSTEMD_UNIT_DIR).join(&unit_file);
+    let bind_capability = systemd_user != "root" && match data.server.as_ref().and_then(|server| server.tls.as_ref()) {
+        Some(TlsInfo::LetsEncrypt { http_port, .. }) => acme::needs_bind_capability(*http_port),
+        _ => false,
+    };
+    let unit = systemd_unit(instance.is_some(), systemd_user, &kakapo_home, &executable, bind_capability);
+
+    let existing_unit = fs::read_to_string(&unit_path).ok();
+    let replace_unit = match existing_unit {
+        Some(ref existing_unit) if *existing_unit != unit && instance.is_some() => Confirmation::with_theme(&theme)
+            .with_text(&format!("{} is shared by all the instances and differs from this one, replace it?", &unit_file))
+            .interact()?,
+        _ => true,
     };
 
-    //TODO: After=network.target postgresql.service mysql.service redis.service rabbitmq-server.service ?
-    let systemd_cfg = format!(r#"
-[Unit]
-Description=Kakapo system service
-After=network.target
-Wants=postgresql.service mysql.service redis.service rabbitmq-server.service
+    if replace_unit {
+        utils::install_system_file(&unit_path, &unit)?;
+        utils::systemctl(&["daemon-reload"])?;
+    }
 
-[Service]
-Type=simple
-User=kakapo
-Group=kakapo
-Environment="KAKAPO_HOME=stuff"
-ExecStart=/usr/bin/boot.clock_fix start
-Restart=on-failure
-RestartSec=10s
+    let unit_name = instances::unit_name(instance);
+    utils::systemctl(&["enable", &unit_name])?;
+    println!("Enabled {}, start it with {}",
+        RGB(131, 221, 2).bold().paint(unit_name.as_str()),
+        RGB(131, 221, 2).bold().paint(format!("sudo systemctl start {}", &unit_name)));
 
-[Install]
-WantedBy=multi-user.target
-"#);
+    navigate(&theme, data)
+}
+
+pub fn has_daemon(data: &ConfigData) -> bool {
+    let instance = data.instance.as_ref().map(|instance| instance.as_str());
+    Command::new("systemctl")
+        .args(&["is-enabled", "--quiet", &instances::unit_name(instance)])
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// The databases on the server, through the ssh tunnel if there is one
+fn list_server_databases(connection: &PostgresConnection, ssh_tunnel: Option<&SshTunnel>) -> Result<Vec<String>, String> {
+    match ssh_tunnel {
+        Some(ssh_tunnel) => {
+            let target = tunnel::forward_target(&connection.to_owned().into_domain());
+            let local_port = tunnel::pick_local_port(ssh_tunnel).map_err(|err| err.to_string())?;
+            // the tunnel stays open until the databases are listed
+            let _opened = tunnel::open(ssh_tunnel, &target, local_port).map_err(|err| err.to_string())?;
+
+            let mut local_connection = connection.to_owned();
+            local_connection.host = tunnel::LOCAL_HOST.to_string();
+            local_connection.port = local_port;
+            domain::list_postgres_databases(&local_connection)
+        },
+        None => domain::list_postgres_databases(connection),
+    }
+}
+
+/// Lists the other databases the user can connect to on the server, the selected ones become domains with the same
+/// credentials and ssh tunnel
+fn add_server_databases(theme: &ColorfulTheme, connection: &PostgresConnection, ssh_tunnel: Option<&SshTunnel>, domains: &mut BTreeMap<String, DomainInfo>) -> Result<(), Box<Error>> {
+    let registered: Vec<String> = domains
+        .values()
+        .filter_map(|domain| match domain {
+            DomainInfo::Postgres { host, port, database, .. } if *host == connection.host && *port == connection.port =>
+                Some(database.to_owned()),
+            _ => None,
+        })
+        .collect();
+
+    // a server that can't be reached shouldn't cost the domain that was just entered
+    let databases = match list_server_databases(connection, ssh_tunnel) {
+        Ok(databases) => databases,
+        Err(err) => {
+            println!("{}", Red.paint(format!("Could not list the databases on {}: {}", &connection.host, err)));
+            return Ok(());
+        },
+    };
 
-    Ok(data)
+    let databases: Vec<String> = databases
+        .into_iter()
+        .filter(|database| !registered.contains(database))
+        .collect();
+
+    if databases.is_empty() {
+        println!("There are no other databases on {}", &connection.host);
+        return Ok(());
+    }
+
+    let selected = Checkboxes::with_theme(theme)
+        .with_prompt("Which databases should become domains?")
+        .items(&databases)
+        .interact()?;
+
+    for idx in selected {
+        let database = databases[idx].to_owned();
+        let name = if database.is_empty() || domains.contains_key(&database) {
+            utils::unique_parrot_name(|name| domains.contains_key(name))
+        } else {
+            database.to_owned()
+        };
+
+        let mut database_connection = connection.to_owned();
+        database_connection.database = database;
+        println!("Added {} as {}", &database_connection.database, RGB(131, 221, 2).bold().paint(name.as_str()));
+        let mut domain = database_connection.into_domain();
+        domain.set_ssh_tunnel(ssh_tunnel.cloned());
+        domains.insert(name, domain);
+    }
+
+    Ok(())
 }
 
-pub fn manage_domains(data: ConfigData, check_if_exists: bool) -> Result<ConfigData, Box<Error>> {
+pub fn manage_domains(data: ConfigData) -> StepResult {
     fn get_add_or_remove_domains(theme: &ColorfulTheme) -> Result<&'static str, Box<Error>> {
         let action = Select::with_theme(theme)
             .with_prompt("Would you like to add or remove a domain?")
@@ -364,34 +749,28 @@ pub fn manage_domains(data: ConfigData, check_if_exists: bool) -> Result<ConfigD
 
                 match domain_type {
                     _ => {
-                        let host: String = Input::with_theme(&theme)
-                            .with_prompt("Database host?")
-                            .default("127.0.0.1".to_string())
-                            .interact()?;
-
-                        let port: u16 = Input::with_theme(&theme)
-                            .with_prompt("Database port?")
-                            .default(5432)
-                            .interact()?;
-
-                        let user: String = Input::with_theme(&theme)
-                            .with_prompt("Database username?")
+                        let connection = connection::prompt(&theme)?;
+                        let behind_bastion = Confirmation::with_theme(&theme)
+                            .with_text("Is the database only reachable through an ssh bastion?")
+                            .default(false)
                             .interact()?;
+                        let ssh_tunnel = if behind_bastion {
+                            Some(connection::prompt_ssh_tunnel(&theme)?)
+                        } else {
+                            None
+                        };
 
-                        let pass: String = PasswordInput::with_theme(&theme)
-                            .with_prompt("Database password?")
-                            .interact()?;
+                        let mut domain = connection.to_owned().into_domain();
+                        domain.set_ssh_tunnel(ssh_tunnel.to_owned());
+                        new_data.domains.insert(domain_name, domain);
 
-                        let database: String = Input::with_theme(&theme)
-                            .with_prompt("Default database?")
-                            .default(user.to_owned())
+                        let add_others = Confirmation::with_theme(&theme)
+                            .with_text("Register other databases on this server as well?")
+                            .default(false)
                             .interact()?;
-
-                        let mut config_value = DomainInfo::Postgres {
-                            host, port, user, pass, database,
-                        };
-
-                        new_data.domains.insert(domain_name, config_value);
+                        if add_others {
+                            add_server_databases(&theme, &connection, ssh_tunnel.as_ref(), &mut new_data.domains)?;
+                        }
                     }
                 }
             },
@@ -411,15 +790,17 @@ pub fn manage_domains(data: ConfigData, check_if_exists: bool) -> Result<ConfigD
             },
             "Let me see my domains" => {
                 println!("");
-                for (domain_name, domain_data) in &new_data.domains {
-                    println!("{}=> {}", RGB(131, 221, 2).bold().paint(format!("{: <16}", &domain_name)), &domain_data);
-                }
+                print_domains(&new_data.domains);
             },
             _ => {
-                return Ok(new_data);
+                return navigate(&theme, new_data);
             },
         };
 
         println!("");
     }
-}
\ No newline at end of file
+}
+
+pub fn has_domains(data: &ConfigData) -> bool {
+    !data.domains.is_empty()
+}
@@ -1,7 +1,23 @@
 
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+use std::str::from_utf8;
+
+use openssl::base64;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
 use rand::Rng;
 use rand;
 
+use error::KakapoError;
+
 pub fn random_parrot_name() -> &'static str {
     //From https://en.wikipedia.org/wiki/List_of_kakapo
     let parrot_names = [
@@ -34,4 +50,239 @@ pub fn random_parrot_name() -> &'static str {
 
     let idx = rand::thread_rng().gen_range(0, parrot_names.len());
     parrot_names[idx]
-}
\ No newline at end of file
+}
+
+/// A parrot name for which `is_taken` is false, numbered if the random picks keep colliding
+pub fn unique_parrot_name<F: Fn(&str) -> bool>(is_taken: F) -> String {
+    for _ in 0..16 {
+        let name = random_parrot_name();
+        if !is_taken(name) {
+            return name.to_string();
+        }
+    }
+
+    let name = random_parrot_name();
+    (2..)
+        .map(|idx| format!("{}{}", name, idx))
+        .find(|name| !is_taken(name))
+        .unwrap_or_else(|| name.to_string())
+}
+
+const PASSWORD_HASH_ITERATIONS: usize = 100_000;
+const PASSWORD_SALT_LEN: usize = 16;
+
+/// `pbkdf2-sha256$ITERATIONS$SALT$HASH` with a random salt, salt and hash in base64
+pub fn hash_password(pass: &str) -> Result<String, ErrorStack> {
+    let mut salt = [0; PASSWORD_SALT_LEN];
+    rand_bytes(&mut salt)?;
+
+    hash_password_with_salt(pass, &salt, PASSWORD_HASH_ITERATIONS)
+}
+
+fn hash_password_with_salt(pass: &str, salt: &[u8], iterations: usize) -> Result<String, ErrorStack> {
+    let mut hash = [0; 32];
+    pbkdf2_hmac(pass.as_bytes(), salt, iterations, MessageDigest::sha256(), &mut hash)?;
+
+    Ok(format!("pbkdf2-sha256${}${}${}", iterations, base64::encode_block(salt), base64::encode_block(&hash)))
+}
+
+/// The user running the wizard, looking through `sudo` if needed
+pub fn operator_name() -> io::Result<String> {
+    if let Ok(sudo_user) = env::var("SUDO_USER") {
+        return Ok(sudo_user);
+    }
+
+    let current_user = Command::new("whoami")
+        .output()?
+        .stdout;
+
+    from_utf8(&current_user)
+        .map(|user| user.trim().to_string())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn sudo(args: &[&str]) -> Result<(), KakapoError> {
+    let command = format!("sudo {}", args.join(" "));
+    let result = Command::new("sudo")
+        .args(args)
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|err| KakapoError::external_command(&command, &err.to_string()))?;
+
+    if result.success() {
+        Ok(())
+    } else {
+        Err(KakapoError::external_command(&command, &result.to_string()))
+    }
+}
+
+/// Adds the user to the group, takes effect on the user's next login
+pub fn add_user_to_group(user: &str, group: &str) -> Result<(), KakapoError> {
+    sudo(&["usermod", "--append", "--groups", group, user])
+}
+
+/// Checks whether `user` can read the file, including any groups they were just added to
+pub fn can_read_as(user: &str, path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    Command::new("sudo")
+        .args(&["-u", user, "test", "-r", &path])
+        .stderr(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// The owner of a file, even if it is in a directory we can't access
+pub fn file_owner(path: &Path) -> Result<String, KakapoError> {
+    let path = path.to_string_lossy();
+    let command = format!("sudo stat --format %U {}", &path);
+    let result = Command::new("sudo")
+        .args(&["stat", "--format", "%U", &path])
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| KakapoError::external_command(&command, &err.to_string()))?;
+
+    if !result.status.success() {
+        return Err(KakapoError::external_command(&command, &format!("Could not find the owner of {}", &path)));
+    }
+
+    from_utf8(&result.stdout)
+        .map(|owner| owner.trim().to_string())
+        .map_err(|err| KakapoError::external_command(&command, &err.to_string()))
+}
+
+/// Reads a file that only the owner and its group can read, if the operator's group membership is not active yet
+pub fn privileged_read(path: &Path) -> Result<String, KakapoError> {
+    let path = path.to_string_lossy();
+    let command = format!("sudo cat {}", &path);
+    let result = Command::new("sudo")
+        .args(&["cat", &path])
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| KakapoError::external_command(&command, &err.to_string()))?;
+
+    if !result.status.success() {
+        return Err(KakapoError::Permission { context: format!("Could not read {}", &path) });
+    }
+
+    String::from_utf8(result.stdout)
+        .map_err(|err| KakapoError::external_command(&command, &err.to_string()))
+}
+
+/// Writes the file through `install`, which creates it with `mode` and `owner:owner` already set, so the data is
+/// never readable by anyone else, not even for a moment
+fn sudo_install(path_str: &str, data: &str, mode: &str, owner: &str) -> Result<(), KakapoError> {
+    let args = ["install", "-m", mode, "-o", owner, "-g", owner, "/dev/stdin", path_str];
+    let command = format!("sudo {}", args.join(" "));
+    let mut child = Command::new("sudo")
+        .args(&args)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|err| KakapoError::external_command(&command, &err.to_string()))?;
+
+    {
+        let stdin = child.stdin
+            .as_mut()
+            .ok_or_else(|| KakapoError::external_command(&command, "Could not write to sudo"))?;
+        stdin.write_all(data.as_bytes())
+            .map_err(|err| KakapoError::external_command(&command, &err.to_string()))?;
+    }
+
+    if !child.wait()?.success() {
+        return Err(KakapoError::Permission { context: format!("Could not write {}", &path_str) });
+    }
+
+    Ok(())
+}
+
+/// Writes a file owned by `owner:owner` with mode 0640 through sudo, then checks that both the owner and the
+/// operator can still read it
+pub fn privileged_write(path: &Path, data: &str, owner: &str) -> Result<(), KakapoError> {
+    let path_str = path.to_string_lossy().into_owned();
+
+    if let Some(parent) = path.parent() {
+        let parent = parent.to_string_lossy();
+        sudo(&["mkdir", "--parents", &parent])?;
+        // only the config's own directory, so the operator can get to the file through the group
+        sudo(&["chmod", "g+rx", &parent])?;
+    }
+
+    sudo_install(&path_str, data, "0640", owner)?;
+
+    let operator = operator_name()?;
+    for user in &[owner.to_string(), operator] {
+        if !can_read_as(user, path) {
+            return Err(KakapoError::Permission { context: format!("{} can't read {}", user, &path_str) });
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies a file the operator can read to `path`, owned by `owner:owner` with `mode`, in a directory that only `owner`
+/// can get into
+pub fn privileged_copy(from: &Path, path: &Path, mode: &str, owner: &str) -> Result<(), KakapoError> {
+    let data = fs::read_to_string(from)
+        .map_err(|err| KakapoError::io(&format!("Could not read {}", from.display()), err))?;
+
+    if let Some(parent) = path.parent() {
+        let parent = parent.to_string_lossy();
+        sudo(&["install", "-d", "-m", "0700", "-o", owner, "-g", owner, &parent])?;
+    }
+
+    sudo_install(&path.to_string_lossy(), &data, mode, owner)
+}
+
+/// Renames a file, through sudo when the operator can't write to its directory, e.g. one owned by kakapo
+pub fn move_file(from: &Path, to: &Path) -> Result<(), KakapoError> {
+    match fs::rename(from, to) {
+        Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied =>
+            sudo(&["mv", "--no-target-directory", &from.to_string_lossy(), &to.to_string_lossy()]),
+        result => result.map_err(|err| KakapoError::io(&format!("Could not move {}", from.display()), err)),
+    }
+}
+
+/// Removes a file, through sudo when the operator can't write to its directory
+pub fn remove_file(path: &Path) -> Result<(), KakapoError> {
+    match fs::remove_file(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied =>
+            sudo(&["rm", "--force", &path.to_string_lossy()]),
+        result => result.map_err(|err| KakapoError::io(&format!("Could not remove {}", path.display()), err)),
+    }
+}
+
+/// Writes a system file, i.e. a systemd unit, owned by root and readable by everyone
+pub fn install_system_file(path: &Path, data: &str) -> Result<(), KakapoError> {
+    let path_str = path.to_string_lossy().into_owned();
+
+    sudo_install(&path_str, data, "0644", "root")
+}
+
+pub fn systemctl(args: &[&str]) -> Result<(), KakapoError> {
+    let mut sudo_args = vec!["systemctl"];
+    sudo_args.extend(args);
+    sudo(&sudo_args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_password, hash_password_with_salt};
+
+    #[test]
+    fn hashes_passwords_with_a_salt() {
+        let hash = hash_password_with_salt("hunter2", b"0123456789abcdef", 1000).unwrap();
+        let parts: Vec<_> = hash.split('$').collect();
+
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], "pbkdf2-sha256");
+        assert_eq!(parts[1], "1000");
+        assert!(!hash.contains("hunter2"));
+        assert_eq!(hash, hash_password_with_salt("hunter2", b"0123456789abcdef", 1000).unwrap());
+        assert!(hash != hash_password_with_salt("hunter3", b"0123456789abcdef", 1000).unwrap());
+        assert!(hash_password("hunter2").unwrap() != hash_password("hunter2").unwrap());
+    }
+}